@@ -0,0 +1,275 @@
+use crate::config;
+use crate::GameEvent;
+
+// Notes as a frequency in Hz, zero for a rest, and a length in milliseconds.
+type Note = (u32, u64);
+
+// The opening of Korobeiniki, with lengths in eighth notes.
+const MELODY: [(u32, u64); 20] = [
+    (659, 2),
+    (494, 1),
+    (523, 1),
+    (587, 2),
+    (523, 1),
+    (494, 1),
+    (440, 2),
+    (440, 1),
+    (523, 1),
+    (659, 2),
+    (587, 1),
+    (523, 1),
+    (494, 3),
+    (523, 1),
+    (587, 2),
+    (659, 2),
+    (523, 2),
+    (440, 2),
+    (440, 2),
+    (0, 2),
+];
+
+// Music gets this much faster with each level, up to a limit.
+const TEMPO_STEP: f64 = 0.92;
+const FASTEST_TEMPO: f64 = 0.4;
+const EIGHTH_MS: f64 = 200.0;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Sound {
+    Move,
+    Rotate,
+    Lock,
+    LineClear(u32),
+    LevelUp,
+    Hold,
+    Danger,
+    GameOver,
+}
+
+impl Sound {
+    pub fn for_event(event: &GameEvent) -> Option<Sound> {
+        match *event {
+            GameEvent::Moved => Some(Sound::Move),
+            GameEvent::Rotated => Some(Sound::Rotate),
+            GameEvent::Locked { .. } => Some(Sound::Lock),
+            GameEvent::LinesCleared { lines, .. } => Some(Sound::LineClear(lines)),
+            GameEvent::LevelUp { .. } => Some(Sound::LevelUp),
+            GameEvent::Held => Some(Sound::Hold),
+            GameEvent::HardDropped => None,
+            GameEvent::Danger => Some(Sound::Danger),
+        }
+    }
+
+    fn notes(self) -> Vec<Note> {
+        match self {
+            Sound::Move => vec![(220, 25)],
+            Sound::Rotate => vec![(330, 30)],
+            Sound::Lock => vec![(110, 50)],
+            // One rising note per line.
+            Sound::LineClear(lines) => [523, 659, 784, 1047, 1319]
+                .iter()
+                .take(lines as usize)
+                .map(|&freq| (freq, 60))
+                .collect(),
+            Sound::LevelUp => vec![(523, 80), (659, 80), (784, 80), (1047, 160)],
+            Sound::Hold => vec![(440, 40), (330, 40)],
+            Sound::Danger => vec![(880, 120), (0, 60), (880, 120)],
+            Sound::GameOver => vec![(392, 150), (330, 150), (262, 150), (196, 300)],
+        }
+    }
+}
+
+/// Plays sound effects and the background music. Without the `audio`
+/// feature, or without a sound device, everything is silent.
+pub struct Audio {
+    settings: config::Audio,
+    output: Option<Output>,
+}
+
+impl Audio {
+    pub fn new(settings: &config::Audio) -> Audio {
+        let output = Output::open();
+        if let Some(output) = &output {
+            output.set_music_volume(music_volume(settings));
+        }
+        Audio {
+            settings: settings.clone(),
+            output,
+        }
+    }
+
+    pub fn set_settings(&mut self, settings: &config::Audio) {
+        self.settings = settings.clone();
+        if let Some(output) = &self.output {
+            output.set_music_volume(music_volume(settings));
+        }
+    }
+
+    pub fn play(&self, sound: Sound) {
+        if let Some(output) = &self.output {
+            if !self.settings.mute {
+                output.play(&sound.notes(), self.settings.volume as f32 / 100.0);
+            }
+        }
+    }
+
+    pub fn play_events(&self, events: &[GameEvent]) {
+        for sound in events.iter().filter_map(Sound::for_event) {
+            self.play(sound);
+        }
+    }
+
+    /// Keeps the music going during a game, speeding up with the level, and
+    /// holds it while the game isn't running.
+    pub fn update_music(&self, playing: bool, level: u32) {
+        let output = match &self.output {
+            Some(output) => output,
+            None => return,
+        };
+        if !playing {
+            output.pause_music();
+            return;
+        }
+        output.resume_music();
+        // Queue the next pass through the melody just before this one ends.
+        if output.queued_music() < 4 {
+            let tempo = TEMPO_STEP
+                .powi(level.saturating_sub(1) as i32)
+                .max(FASTEST_TEMPO);
+            let notes: Vec<Note> = MELODY
+                .iter()
+                .map(|&(freq, eighths)| (freq, (eighths as f64 * EIGHTH_MS * tempo) as u64))
+                .collect();
+            output.queue_music(&notes);
+        }
+    }
+
+    /// Drops the rest of the music so the next game starts it afresh.
+    pub fn stop_music(&mut self) {
+        if let Some(output) = &mut self.output {
+            output.stop_music();
+        }
+    }
+}
+
+fn music_volume(settings: &config::Audio) -> f32 {
+    if settings.mute {
+        0.0
+    } else {
+        settings.music_volume as f32 / 100.0
+    }
+}
+
+#[cfg(feature = "audio")]
+struct Output {
+    device: rodio::Device,
+    music: rodio::Sink,
+}
+
+#[cfg(feature = "audio")]
+impl Output {
+    fn open() -> Option<Output> {
+        let device = rodio::default_output_device()?;
+        let music = Output::sink(&device)?;
+        Some(Output { device, music })
+    }
+
+    // Some audio backends panic rather than report a broken device.
+    fn sink(device: &rodio::Device) -> Option<rodio::Sink> {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+        catch_unwind(AssertUnwindSafe(|| rodio::Sink::new(device))).ok()
+    }
+
+    fn tone(freq: u32, ms: u64, amplitude: f32) -> impl rodio::Source<Item = f32> + Send {
+        use rodio::Source;
+        use std::time::Duration;
+        rodio::source::SineWave::new(freq)
+            .take_duration(Duration::from_millis(ms))
+            .fade_in(Duration::from_millis(5))
+            .amplify(amplitude)
+    }
+
+    fn play(&self, notes: &[Note], volume: f32) {
+        let sink = match Output::sink(&self.device) {
+            Some(sink) => sink,
+            None => return,
+        };
+        sink.set_volume(volume);
+        for &(freq, ms) in notes {
+            sink.append(Output::tone(freq, ms, 0.25));
+        }
+        sink.detach();
+    }
+
+    fn set_music_volume(&self, volume: f32) {
+        self.music.set_volume(volume);
+    }
+
+    fn queued_music(&self) -> usize {
+        self.music.len()
+    }
+
+    fn queue_music(&self, notes: &[Note]) {
+        for &(freq, ms) in notes {
+            self.music.append(Output::tone(freq, ms, 0.15));
+        }
+    }
+
+    fn pause_music(&self) {
+        self.music.pause();
+    }
+
+    fn resume_music(&self) {
+        self.music.play();
+    }
+
+    fn stop_music(&mut self) {
+        // A stopped sink stays stopped, so start over with a new one.
+        let volume = self.music.volume();
+        match Output::sink(&self.device) {
+            Some(music) => {
+                self.music = music;
+                self.music.set_volume(volume);
+            }
+            None => self.music.stop(),
+        }
+    }
+}
+
+// Without the feature there is never any output to play on.
+#[cfg(not(feature = "audio"))]
+enum Output {}
+
+#[cfg(not(feature = "audio"))]
+impl Output {
+    fn open() -> Option<Output> {
+        None
+    }
+
+    fn play(&self, _notes: &[Note], _volume: f32) {
+        match *self {}
+    }
+
+    fn set_music_volume(&self, _volume: f32) {
+        match *self {}
+    }
+
+    fn queued_music(&self) -> usize {
+        match *self {}
+    }
+
+    fn queue_music(&self, _notes: &[Note]) {
+        match *self {}
+    }
+
+    fn pause_music(&self) {
+        match *self {}
+    }
+
+    fn resume_music(&self) {
+        match *self {}
+    }
+
+    fn stop_music(&mut self) {
+        match *self {}
+    }
+}
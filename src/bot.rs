@@ -0,0 +1,492 @@
+use crate::pathfind::{self, Input, Placement};
+use crate::{Board, Piece, Tetris, BLANK};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+use std::time::Duration;
+
+// The Tetris Bot Protocol always describes a 40 row board.
+const TBP_BOARD_HEIGHT: usize = 40;
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum FrontendMessage {
+    /// `hold` isn't in the protocol yet, so bots may ignore it. Any hold
+    /// moves they suggest when it is off are rejected like other
+    /// unreachable moves.
+    Rules {
+        hold: bool,
+    },
+    Start {
+        hold: Option<String>,
+        queue: Vec<String>,
+        combo: u32,
+        back_to_back: bool,
+        board: Vec<Vec<Option<String>>>,
+    },
+    Suggest,
+    Play {
+        #[serde(rename = "move")]
+        mv: Move,
+    },
+    NewPiece {
+        piece: String,
+    },
+    Stop,
+    Quit,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum BotMessage {
+    Info {
+        name: String,
+        version: String,
+    },
+    Ready,
+    Error {
+        reason: String,
+    },
+    Suggestion {
+        moves: Vec<Move>,
+    },
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Move {
+    location: Location,
+    #[serde(default = "no_spin")]
+    spin: String,
+}
+
+fn no_spin() -> String {
+    "none".to_string()
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Location {
+    #[serde(rename = "type")]
+    kind: String,
+    orientation: Orientation,
+    x: isize,
+    y: isize,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Orientation {
+    North,
+    East,
+    South,
+    West,
+}
+
+impl Location {
+    /// The cells this location covers, in board coordinates with y pointing
+    /// down. Offsets are the guideline north orientations, rotated clockwise
+    /// as many times as the orientation asks.
+    fn cells(&self, board: &Board) -> Option<HashSet<(isize, isize)>> {
+        let north: [(isize, isize); 4] = match self.kind.as_str() {
+            "I" => [(-1, 0), (0, 0), (1, 0), (2, 0)],
+            "O" => [(0, 0), (1, 0), (0, 1), (1, 1)],
+            "T" => [(-1, 0), (0, 0), (1, 0), (0, 1)],
+            "S" => [(-1, 0), (0, 0), (0, 1), (1, 1)],
+            "Z" => [(-1, 1), (0, 1), (0, 0), (1, 0)],
+            "J" => [(-1, 1), (-1, 0), (0, 0), (1, 0)],
+            "L" => [(-1, 0), (0, 0), (1, 0), (1, 1)],
+            _ => return None,
+        };
+        let turns = match self.orientation {
+            Orientation::North => 0,
+            Orientation::East => 1,
+            Orientation::South => 2,
+            Orientation::West => 3,
+        };
+        Some(
+            north
+                .iter()
+                .map(|&(dx, dy)| {
+                    let (dx, dy) = (0..turns).fold((dx, dy), |(x, y), _| (y, -x));
+                    (self.x + dx, board.height as isize - 1 - (self.y + dy))
+                })
+                .collect(),
+        )
+    }
+
+    /// Finds the rotation and offset of `piece` that covers the same cells.
+    fn placement_for(&self, board: &Board, piece: &Piece) -> Option<Placement> {
+        if self.kind != piece.shape.name() {
+            return None;
+        }
+        let target = self.cells(board)?;
+        let (min_x, min_y) = target.iter().min_by_key(|&&(x, y)| (y, x))?;
+        (0..piece.rotations()).find_map(|rotation| {
+            let cells = template_cells(piece, rotation);
+            let (tx, ty) = cells.iter().min_by_key(|&&(x, y)| (y, x))?;
+            let (x, y) = (min_x - tx, min_y - ty);
+            let moved: HashSet<_> = cells.iter().map(|&(cx, cy)| (cx + x, cy + y)).collect();
+            if moved == target {
+                Some(Placement { x, y, rotation })
+            } else {
+                None
+            }
+        })
+    }
+}
+
+fn template_cells(piece: &Piece, rotation: usize) -> Vec<(isize, isize)> {
+    let template = piece.shape.template()[rotation];
+    let mut cells = Vec::new();
+    for (y, row) in template.iter().enumerate() {
+        for (x, cell) in row.iter().enumerate() {
+            if *cell != BLANK {
+                cells.push((x as isize, y as isize));
+            }
+        }
+    }
+    cells
+}
+
+fn board_rows(board: &Board) -> Vec<Vec<Option<String>>> {
+    (0..TBP_BOARD_HEIGHT)
+        .map(|tbp_y| {
+            (0..board.width)
+                .map(|x| {
+                    let y = board.height as isize - 1 - tbp_y as isize;
+                    if !board.contains(x as isize, y) {
+                        return None;
+                    }
+                    board.get(x as isize, y).map(|_| "G".to_string())
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// An external bot process speaking the Tetris Bot Protocol over its
+/// standard input and output, one JSON message per line.
+pub struct Bot {
+    child: Child,
+    stdin: ChildStdin,
+    messages: Receiver<BotMessage>,
+    ready: bool,
+    started: bool,
+    // The piece count the bot was last told about, if it is in sync.
+    synced_piece: Option<u32>,
+    suggestion: Option<Vec<Move>>,
+}
+
+impl Bot {
+    pub fn spawn(command: &str) -> std::io::Result<Bot> {
+        let mut parts = command.split_whitespace();
+        let program = parts.next().ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "empty bot command")
+        })?;
+        let mut child = Command::new(program)
+            .args(parts)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+        let (sender, messages) = channel();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(_) => break,
+                };
+                match serde_json::from_str(&line) {
+                    Ok(message) => {
+                        if sender.send(message).is_err() {
+                            break;
+                        }
+                    }
+                    Err(err) => eprintln!("Ignoring bot message {:?}: {}", line, err),
+                }
+            }
+        });
+        Ok(Bot {
+            child,
+            stdin,
+            messages,
+            ready: false,
+            started: false,
+            synced_piece: None,
+            suggestion: None,
+        })
+    }
+
+    fn send(&mut self, message: &FrontendMessage) {
+        let line = serde_json::to_string(message).expect("Unable to encode bot message");
+        if let Err(err) = writeln!(self.stdin, "{}", line).and_then(|_| self.stdin.flush()) {
+            eprintln!("Unable to write to bot: {}", err);
+        }
+    }
+
+    /// Exchanges messages with the bot and plays its chosen move for the
+    /// falling piece. Suggestions are checked against the pathfinder before
+    /// being played, so a bot can never make a move a player could not.
+    pub fn update(&mut self, tetris: &mut Tetris) {
+        while let Ok(message) = self.messages.try_recv() {
+            match message {
+                BotMessage::Info { name, version } => {
+                    eprintln!("Bot: {} {}", name, version);
+                    let hold = tetris.settings.ruleset.hold;
+                    self.send(&FrontendMessage::Rules { hold });
+                }
+                BotMessage::Ready => self.ready = true,
+                BotMessage::Error { reason } => eprintln!("Bot error: {}", reason),
+                BotMessage::Suggestion { moves } => self.suggestion = Some(moves),
+                BotMessage::Unknown => {}
+            }
+        }
+        if !self.ready || tetris.falling_piece.is_none() {
+            return;
+        }
+
+        if self.synced_piece != Some(tetris.piece_count) {
+            if self.synced_piece.map(|piece| piece + 1) == Some(tetris.piece_count) {
+                let piece = tetris.next_piece.shape.name().to_string();
+                self.send(&FrontendMessage::NewPiece { piece });
+            } else {
+                if self.started {
+                    self.send(&FrontendMessage::Stop);
+                }
+                self.started = true;
+                let falling_piece = tetris.falling_piece.as_ref().unwrap();
+                let hold = match &tetris.held_piece {
+                    Some(held) if tetris.settings.ruleset.hold => {
+                        Some(held.shape.name().to_string())
+                    }
+                    _ => None,
+                };
+                self.send(&FrontendMessage::Start {
+                    hold,
+                    queue: vec![
+                        falling_piece.shape.name().to_string(),
+                        tetris.next_piece.shape.name().to_string(),
+                    ],
+                    combo: 0,
+                    back_to_back: false,
+                    board: board_rows(&tetris.board),
+                });
+            }
+            self.synced_piece = Some(tetris.piece_count);
+            self.suggestion = None;
+            self.send(&FrontendMessage::Suggest);
+            return;
+        }
+
+        let moves = match self.suggestion.take() {
+            Some(moves) => moves,
+            None => return,
+        };
+        // A move for another piece type is a hold, bringing in the held
+        // piece, or the next one if nothing is held yet. It is only played
+        // when that piece has room to spawn.
+        let board = &tetris.board;
+        let fp = tetris.falling_piece.as_ref().unwrap();
+        let mut swap = match &tetris.held_piece {
+            Some(held) => held.clone(),
+            None => tetris.next_piece.clone(),
+        };
+        board.move_to_spawn(&mut swap);
+        let can_hold = tetris.settings.ruleset.hold
+            && !tetris.hold_used
+            && board.is_valid_position(&swap, 0, 0);
+        let chosen = moves.into_iter().find_map(|mv| {
+            let (piece, held) = if mv.location.kind == fp.shape.name() {
+                (fp, false)
+            } else if can_hold {
+                (&swap, true)
+            } else {
+                return None;
+            };
+            let target = mv.location.placement_for(board, piece)?;
+            let path = pathfind::find_path(board, piece, target)?;
+            Some((mv, held, path))
+        });
+        match chosen {
+            Some((mv, held, path)) => {
+                self.send(&FrontendMessage::Play { mv });
+                if held {
+                    let count = tetris.piece_count;
+                    tetris.hold();
+                    // Holding into an empty slot used up the next piece.
+                    if tetris.piece_count != count {
+                        let piece = tetris.next_piece.shape.name().to_string();
+                        self.send(&FrontendMessage::NewPiece { piece });
+                        self.synced_piece = Some(tetris.piece_count);
+                    }
+                }
+                tetris.inputs += pathfind::cost(&path);
+                let fp = tetris.falling_piece.as_mut().unwrap();
+                for input in path {
+                    pathfind::apply(&tetris.board, fp, input);
+                }
+                tetris.gravity_on = true;
+            }
+            None => {
+                // Drop the piece where it is and resynchronise on the next one.
+                eprintln!("Bot suggested no reachable move");
+                let fp = tetris.falling_piece.as_mut().unwrap();
+                pathfind::apply(&tetris.board, fp, Input::HardDrop);
+                tetris.gravity_on = true;
+                self.synced_piece = None;
+            }
+        }
+    }
+}
+
+impl Drop for Bot {
+    fn drop(&mut self) {
+        self.send(&FrontendMessage::Quit);
+        thread::sleep(Duration::from_millis(100));
+        if let Ok(None) = self.child.try_wait() {
+            let _ = self.child.kill();
+        }
+        let _ = self.child.wait();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{BoardSize, Settings};
+    use crate::skin::Skin;
+    use crate::theme::Theme;
+    use crate::{shapes, Mode};
+    use std::fs;
+    use std::rc::Rc;
+    use std::time::Instant;
+
+    // Answers every request for a suggestion with a move to the floor for
+    // each piece type, whatever the board.
+    const FAKE_BOT: &str = r#"
+echo '{"type":"info","name":"fake","version":"1"}'
+while read -r line; do
+    case "$line" in
+        *'"type":"rules"'*) echo '{"type":"ready"}' ;;
+        *'"type":"suggest"'*) echo '{"type":"suggestion","moves":[
+            {"location":{"type":"I","orientation":"north","x":4,"y":0}},
+            {"location":{"type":"O","orientation":"north","x":4,"y":0}},
+            {"location":{"type":"T","orientation":"north","x":4,"y":0}},
+            {"location":{"type":"S","orientation":"north","x":4,"y":0}},
+            {"location":{"type":"Z","orientation":"north","x":4,"y":0}},
+            {"location":{"type":"J","orientation":"north","x":4,"y":0}},
+            {"location":{"type":"L","orientation":"north","x":4,"y":0}}]}' | tr -d '\n'; echo ;;
+        *'"type":"quit"'*) exit 0 ;;
+    esac
+done
+"#;
+
+    fn piece(name: &str) -> Piece {
+        let set = shapes::load(None).unwrap();
+        let shape = set
+            .shapes
+            .iter()
+            .find(|shape| shape.name() == name)
+            .unwrap();
+        Piece {
+            shape: shape.clone(),
+            rotation: 0,
+            x: 0,
+            y: 0,
+            color: 0,
+        }
+    }
+
+    fn at(kind: &str, orientation: Orientation, x: isize, y: isize) -> Location {
+        Location {
+            kind: kind.to_string(),
+            orientation,
+            x,
+            y,
+        }
+    }
+
+    #[test]
+    fn converts_tbp_coordinates() {
+        let board = Board::new(&BoardSize::default());
+        // TBP counts rows up from the floor, the board counts them down.
+        let north = at("T", Orientation::North, 4, 0);
+        let cells: HashSet<_> = vec![(3, 19), (4, 19), (5, 19), (4, 18)]
+            .into_iter()
+            .collect();
+        assert_eq!(north.cells(&board), Some(cells));
+        let east = at("T", Orientation::East, 4, 1);
+        let cells: HashSet<_> = vec![(4, 17), (4, 18), (4, 19), (5, 18)]
+            .into_iter()
+            .collect();
+        assert_eq!(east.cells(&board), Some(cells));
+    }
+
+    #[test]
+    fn places_each_tetromino_north_and_east() {
+        let board = Board::new(&BoardSize::default());
+        for &kind in ["I", "O", "T", "S", "Z", "J", "L"].iter() {
+            for &orientation in [Orientation::North, Orientation::East].iter() {
+                let location = at(kind, orientation, 4, 5);
+                let mut piece = piece(kind);
+                let placement = location.placement_for(&board, &piece).unwrap();
+                piece.x = placement.x;
+                piece.y = placement.y;
+                piece.rotation = placement.rotation;
+                let cells: HashSet<_> = piece.cells().into_iter().collect();
+                assert_eq!(
+                    Some(cells),
+                    location.cells(&board),
+                    "{} {:?}",
+                    kind,
+                    orientation
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_a_location_for_another_piece() {
+        let board = Board::new(&BoardSize::default());
+        let location = at("T", Orientation::North, 4, 5);
+        assert_eq!(location.placement_for(&board, &piece("L")), None);
+        let location = at("X", Orientation::North, 4, 5);
+        assert_eq!(location.cells(&board), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn plays_a_suggested_move() {
+        let script = std::env::temp_dir().join(format!("fake-bot-{}.sh", std::process::id()));
+        fs::write(&script, FAKE_BOT).unwrap();
+        let mut bot = Bot::spawn(&format!("sh {}", script.display())).unwrap();
+        let mut settings = Settings::default();
+        settings.ruleset.hold = false;
+        let shapes = shapes::load(None).unwrap();
+        let mut tetris = Tetris::new(
+            Mode::Marathon,
+            &settings,
+            &shapes,
+            &Rc::new(Theme::default()),
+            &Rc::new(Skin::Flat),
+        );
+        let fp = tetris.falling_piece.clone().unwrap();
+        let target = at(fp.shape.name(), Orientation::North, 4, 0)
+            .placement_for(&tetris.board, &fp)
+            .unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while tetris.falling_piece.as_ref().map(Placement::of) != Some(target) {
+            assert!(Instant::now() < deadline, "the bot never played a move");
+            bot.update(&mut tetris);
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(tetris.gravity_on);
+        drop(bot);
+        fs::remove_file(&script).unwrap();
+    }
+}
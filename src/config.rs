@@ -0,0 +1,485 @@
+use crate::Mode;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Everything that used to be a compile-time constant, read from
+/// `settings.toml` in the user config directory or the file given with
+/// `--config`. Missing values keep their defaults.
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub handling: Handling,
+    pub display: Display,
+    pub effects: Effects,
+    pub audio: Audio,
+    pub board: BoardSize,
+    pub ruleset: Ruleset,
+    pub assist: Assist,
+    pub game: Game,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Handling {
+    /// Delay between repeated sideways moves while a direction is held.
+    pub auto_repeat_ms: u64,
+    /// Delay between repeated soft drops while down is held.
+    pub soft_drop_ms: u64,
+}
+
+impl Default for Handling {
+    fn default() -> Handling {
+        Handling {
+            auto_repeat_ms: 150,
+            soft_drop_ms: 100,
+        }
+    }
+}
+
+impl Handling {
+    pub fn auto_repeat(&self) -> Duration {
+        Duration::from_millis(self.auto_repeat_ms)
+    }
+
+    pub fn soft_drop(&self) -> Duration {
+        Duration::from_millis(self.soft_drop_ms)
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Display {
+    pub fps: u64,
+    pub width: u32,
+    pub height: u32,
+    pub box_size: u32,
+    /// Start in fullscreen. The window can be resized either way, and
+    /// everything scales to fit.
+    pub fullscreen: bool,
+    /// A built-in theme, a theme in the user themes directory or a path to
+    /// a theme file.
+    pub theme: String,
+    /// "flat", a built-in skin, a skin in the user skins directory or a
+    /// path to a skin file.
+    pub skin: String,
+    /// Draw a pattern on each block that differs between shapes, so pieces
+    /// can be told apart without their colors.
+    pub block_patterns: bool,
+    /// Warn once the stack reaches this many rows from the top of the
+    /// field. Zero turns the warning off.
+    pub danger_rows: usize,
+}
+
+impl Default for Display {
+    fn default() -> Display {
+        Display {
+            fps: 25,
+            width: 640,
+            height: 480,
+            box_size: 20,
+            fullscreen: false,
+            theme: "guideline".to_string(),
+            skin: "flat".to_string(),
+            block_patterns: false,
+            danger_rows: 4,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Effects {
+    /// Bursts of particles from cleared rows.
+    pub particles: bool,
+    /// Shake the board on hard drops.
+    pub screen_shake: bool,
+    /// Name clears, back-to-backs and combos as they happen.
+    pub popups: bool,
+}
+
+impl Default for Effects {
+    fn default() -> Effects {
+        Effects {
+            particles: true,
+            screen_shake: true,
+            popups: true,
+        }
+    }
+}
+
+impl Effects {
+    pub fn any(&self) -> bool {
+        self.particles || self.screen_shake || self.popups
+    }
+
+    pub fn all(&self) -> bool {
+        self.particles && self.screen_shake && self.popups
+    }
+
+    pub fn set_all(&mut self, on: bool) {
+        self.particles = on;
+        self.screen_shake = on;
+        self.popups = on;
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Audio {
+    /// Sound effect volume, in percent.
+    pub volume: u32,
+    /// Music volume, in percent.
+    pub music_volume: u32,
+    pub mute: bool,
+}
+
+impl Default for Audio {
+    fn default() -> Audio {
+        Audio {
+            volume: 80,
+            music_volume: 50,
+            mute: false,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BoardSize {
+    pub width: usize,
+    pub height: usize,
+    /// Hidden rows above the visible field that pieces can lock into.
+    pub buffer: usize,
+}
+
+impl Default for BoardSize {
+    fn default() -> BoardSize {
+        BoardSize {
+            width: 10,
+            height: 20,
+            buffer: 20,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Ruleset {
+    pub hold: bool,
+    /// Spawn pieces in a random orientation rather than their first one.
+    pub random_spawn_rotation: bool,
+    /// End the game when a piece locks entirely above the visible field.
+    pub lock_out: bool,
+    /// With `lock_out`, any part of the piece above the field is enough.
+    pub partial_lock_out: bool,
+    /// End the game when garbage pushes blocks off the top of the buffer.
+    /// Otherwise those blocks are lost.
+    pub garbage_out: bool,
+    /// Add a garbage row every this many pieces. Zero turns garbage off.
+    pub garbage_interval: u32,
+    /// How long full rows show before they collapse, in frames at 60 a
+    /// second as classic rulesets count them.
+    pub line_clear_frames: u32,
+    /// The entry delay (ARE) between a piece locking, or its lines
+    /// clearing, and the next piece appearing, in frames at 60 a second.
+    pub entry_delay_frames: u32,
+}
+
+impl Default for Ruleset {
+    fn default() -> Ruleset {
+        Ruleset {
+            hold: true,
+            random_spawn_rotation: true,
+            lock_out: true,
+            partial_lock_out: false,
+            garbage_out: true,
+            garbage_interval: 0,
+            line_clear_frames: 15,
+            entry_delay_frames: 0,
+        }
+    }
+}
+
+fn frames(count: u32) -> Duration {
+    Duration::from_micros(count as u64 * 1_000_000 / 60)
+}
+
+impl Ruleset {
+    pub fn line_clear(&self) -> Duration {
+        frames(self.line_clear_frames)
+    }
+
+    pub fn entry_delay(&self) -> Duration {
+        frames(self.entry_delay_frames)
+    }
+}
+
+/// Options that make the game easier to play. Games with any of them on,
+/// apart from `high_contrast`, keep their high scores separate.
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Assist {
+    /// How fast pieces fall, in percent of the normal speed for the level.
+    pub gravity_percent: u32,
+    /// How long a landed piece waits before locking, in percent of the
+    /// normal delay for the level.
+    pub lock_delay_percent: u32,
+    /// Pieces stay where they spawn until they are first dropped.
+    pub gravity_after_drop: bool,
+    /// Black and white screens, and brighter, outlined pieces.
+    pub high_contrast: bool,
+}
+
+impl Default for Assist {
+    fn default() -> Assist {
+        Assist {
+            gravity_percent: 100,
+            lock_delay_percent: 100,
+            gravity_after_drop: false,
+            high_contrast: false,
+        }
+    }
+}
+
+impl Assist {
+    /// Whether anything that changes how the game plays is on.
+    pub fn in_use(&self) -> bool {
+        self.gravity_percent != 100 || self.lock_delay_percent != 100 || self.gravity_after_drop
+    }
+
+    /// The time between falls, given the normal time for the level.
+    pub fn fall_interval(&self, normal: Duration) -> Duration {
+        normal.mul_f64(100.0 / self.gravity_percent as f64)
+    }
+
+    pub fn lock_delay(&self, normal: Duration) -> Duration {
+        normal.mul_f64(self.lock_delay_percent as f64 / 100.0)
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Game {
+    /// The mode played when none is given on the command line.
+    pub mode: Mode,
+    /// A shape data file to play with instead of the standard tetrominoes.
+    pub shapes: Option<PathBuf>,
+}
+
+impl Default for Game {
+    fn default() -> Game {
+        Game {
+            mode: Mode::Marathon,
+            shapes: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(PathBuf, String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, err) => write!(f, "Could not read {}: {}", path.display(), err),
+            ConfigError::Parse(path, err) => write!(f, "Invalid {}: {}", path.display(), err),
+            ConfigError::Invalid(path, reason) => {
+                write!(f, "Invalid {}: {}", path.display(), reason)
+            }
+        }
+    }
+}
+
+/// Reads a file written by `save_file`, or gives the default when there is
+/// no file yet. A file that can't be understood is an error rather than
+/// being replaced with the default on the next save.
+pub fn load_file<T: DeserializeOwned + Default>(path: Option<PathBuf>) -> Result<T, ConfigError> {
+    let path = match path {
+        Some(path) => path,
+        None => return Ok(T::default()),
+    };
+    match fs::read_to_string(&path) {
+        Ok(contents) => toml::from_str(&contents).map_err(|err| ConfigError::Parse(path, err)),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(T::default()),
+        Err(err) => Err(ConfigError::Read(path, err)),
+    }
+}
+
+/// Writes `value` as TOML, creating the directory it goes in. `path` is None
+/// when there is no config or data directory to write to.
+pub fn save_file<T: Serialize>(value: &T, path: Option<PathBuf>) -> io::Result<()> {
+    let path =
+        path.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no directory to save to"))?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // Going through a Value puts plain keys ahead of the nested tables.
+    let value = toml::Value::try_from(value)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    let contents =
+        toml::to_string(&value).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    fs::write(path, contents)
+}
+
+fn check(ok: bool, reason: impl FnOnce() -> String) -> Result<(), String> {
+    if ok {
+        Ok(())
+    } else {
+        Err(reason())
+    }
+}
+
+impl Settings {
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("tetris").join("settings.toml"))
+    }
+
+    /// Reads the given settings file, or the per-user one if there is no
+    /// override. Only an explicitly requested file has to exist.
+    pub fn load(path: Option<&Path>) -> Result<Settings, ConfigError> {
+        let (path, required) = match path {
+            Some(path) => (path.to_path_buf(), true),
+            None => match Settings::default_path() {
+                Some(path) => (path, false),
+                None => return Ok(Settings::default()),
+            },
+        };
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(ref err) if !required && err.kind() == io::ErrorKind::NotFound => {
+                return Ok(Settings::default())
+            }
+            Err(err) => return Err(ConfigError::Read(path, err)),
+        };
+        let settings: Settings =
+            toml::from_str(&contents).map_err(|err| ConfigError::Parse(path.clone(), err))?;
+        settings
+            .validate()
+            .map_err(|reason| ConfigError::Invalid(path, reason))?;
+        Ok(settings)
+    }
+
+    /// Writes the settings back to the file they were read from.
+    pub fn save(&self, path: Option<&Path>) -> io::Result<()> {
+        let path = path.map(Path::to_path_buf).or_else(Settings::default_path);
+        save_file(self, path)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let display = &self.display;
+        check((1..=240).contains(&display.fps), || {
+            format!("display.fps must be between 1 and 240, not {}", display.fps)
+        })?;
+        check((4..=64).contains(&display.box_size), || {
+            format!(
+                "display.box_size must be between 4 and 64, not {}",
+                display.box_size
+            )
+        })?;
+        // Boxes shrink to fit, but the text panels need some room.
+        check(display.width >= 480 && display.height >= 360, || {
+            format!(
+                "display size must be at least 480x360, not {}x{}",
+                display.width, display.height
+            )
+        })?;
+        let board = &self.board;
+        check((4..=50).contains(&board.width), || {
+            format!("board.width must be between 4 and 50, not {}", board.width)
+        })?;
+        check((4..=100).contains(&board.height), || {
+            format!(
+                "board.height must be between 4 and 100, not {}",
+                board.height
+            )
+        })?;
+        // Pieces spawn with their template two rows above the field.
+        check((2..=40).contains(&board.buffer), || {
+            format!(
+                "board.buffer must be between 2 and 40, not {}",
+                board.buffer
+            )
+        })?;
+        check(display.danger_rows <= board.height, || {
+            format!(
+                "display.danger_rows must be at most board.height, not {}",
+                display.danger_rows
+            )
+        })?;
+        let handling = &self.handling;
+        check((1..=1000).contains(&handling.auto_repeat_ms), || {
+            "handling.auto_repeat_ms must be between 1 and 1000".to_string()
+        })?;
+        check((1..=1000).contains(&handling.soft_drop_ms), || {
+            "handling.soft_drop_ms must be between 1 and 1000".to_string()
+        })?;
+        let ruleset = &self.ruleset;
+        check(ruleset.line_clear_frames <= 120, || {
+            "ruleset.line_clear_frames must be at most 120".to_string()
+        })?;
+        check(ruleset.entry_delay_frames <= 120, || {
+            "ruleset.entry_delay_frames must be at most 120".to_string()
+        })?;
+        let assist = &self.assist;
+        check((10..=100).contains(&assist.gravity_percent), || {
+            format!(
+                "assist.gravity_percent must be between 10 and 100, not {}",
+                assist.gravity_percent
+            )
+        })?;
+        check((100..=1000).contains(&assist.lock_delay_percent), || {
+            format!(
+                "assist.lock_delay_percent must be between 100 and 1000, not {}",
+                assist.lock_delay_percent
+            )
+        })?;
+        let audio = &self.audio;
+        check(audio.volume <= 100, || {
+            format!("audio.volume must be at most 100, not {}", audio.volume)
+        })?;
+        check(audio.music_volume <= 100, || {
+            format!(
+                "audio.music_volume must be at most 100, not {}",
+                audio.music_volume
+            )
+        })?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default, Deserialize, PartialEq, Serialize, Debug)]
+    struct Data {
+        value: u32,
+    }
+
+    #[test]
+    fn saves_and_loads_files() {
+        let dir = std::env::temp_dir().join(format!("tetris-config-{}", std::process::id()));
+        let path = dir.join("data.toml");
+        let loaded: Data = load_file(Some(path.clone())).unwrap();
+        assert_eq!(loaded, Data::default());
+        save_file(&Data { value: 3 }, Some(path.clone())).unwrap();
+        let loaded: Data = load_file(Some(path.clone())).unwrap();
+        assert_eq!(loaded, Data { value: 3 });
+        // A file that doesn't parse is reported, not treated as empty.
+        fs::write(&path, "value = \"three\"").unwrap();
+        assert!(matches!(
+            load_file::<Data>(Some(path)),
+            Err(ConfigError::Parse(..))
+        ));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
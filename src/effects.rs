@@ -0,0 +1,213 @@
+use crate::config;
+use crate::{CenterText, GameEvent, Layout};
+use graphics::text::Text;
+use graphics::{Context, Rectangle, Transformed};
+use opengl_graphics::{GlGraphics, GlyphCache};
+use rand::Rng;
+use std::time::Instant;
+
+// Distances are in layout pixels and times in seconds.
+const GRAVITY: f64 = 900.0;
+const PARTICLES_PER_CELL: usize = 3;
+const SHAKE_TIME: f64 = 0.15;
+const SHAKE_SIZE: f64 = 4.0;
+const POPUP_TIME: f64 = 1.2;
+const POPUP_RISE: f64 = 30.0;
+// A long gap between updates, like a pause, only moves things on this much.
+const MAX_STEP: f64 = 0.1;
+
+struct Particle {
+    x: f64,
+    y: f64,
+    dx: f64,
+    dy: f64,
+    age: f64,
+    life: f64,
+}
+
+struct Popup {
+    lines: Vec<String>,
+    age: f64,
+}
+
+/// Particles, board shake and score popups, set off by the events a game
+/// emits and drawn over the board.
+pub struct Effects {
+    settings: config::Effects,
+    particles: Vec<Particle>,
+    shake: f64,
+    popup: Option<Popup>,
+    updated_at: Instant,
+}
+
+impl Effects {
+    pub fn new(settings: &config::Effects) -> Effects {
+        Effects {
+            settings: settings.clone(),
+            particles: Vec::new(),
+            shake: 0.0,
+            popup: None,
+            updated_at: Instant::now(),
+        }
+    }
+
+    pub fn set_settings(&mut self, settings: &config::Effects) {
+        self.settings = settings.clone();
+        if !settings.particles {
+            self.particles.clear();
+        }
+        if !settings.screen_shake {
+            self.shake = 0.0;
+        }
+        if !settings.popups {
+            self.popup = None;
+        }
+    }
+
+    /// Starts effects for new events on a board `columns` wide, and moves the
+    /// running ones on.
+    pub fn update(&mut self, events: &[GameEvent], layout: &Layout, columns: usize) {
+        let step = self.updated_at.elapsed().as_secs_f64().min(MAX_STEP);
+        self.updated_at = Instant::now();
+        for particle in &mut self.particles {
+            particle.x += particle.dx * step;
+            particle.y += particle.dy * step;
+            particle.dy += GRAVITY * step;
+            particle.age += step;
+        }
+        self.particles
+            .retain(|particle| particle.age < particle.life);
+        self.shake = (self.shake - step).max(0.0);
+        if let Some(popup) = &mut self.popup {
+            popup.age += step;
+            if popup.age >= POPUP_TIME {
+                self.popup = None;
+            }
+        }
+
+        for event in events {
+            match event {
+                GameEvent::HardDropped if self.settings.screen_shake => self.shake = SHAKE_TIME,
+                // A spin that clears lines is named along with them.
+                GameEvent::Locked { t_spin: true }
+                    if self.settings.popups
+                        && !events
+                            .iter()
+                            .any(|event| matches!(event, GameEvent::LinesCleared { .. })) =>
+                {
+                    self.show(vec!["T-SPIN".to_string()]);
+                }
+                GameEvent::LinesCleared {
+                    lines,
+                    combo,
+                    t_spin,
+                    back_to_back,
+                    rows,
+                } => {
+                    if self.settings.particles {
+                        self.burst(rows, layout, columns);
+                    }
+                    if self.settings.popups {
+                        self.show(clear_popup(*lines, *combo, *t_spin, *back_to_back));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn show(&mut self, lines: Vec<String>) {
+        if !lines.is_empty() {
+            self.popup = Some(Popup { lines, age: 0.0 });
+        }
+    }
+
+    // Throws particles up and out from every cell of the cleared rows.
+    fn burst(&mut self, rows: &[isize], layout: &Layout, columns: usize) {
+        let mut rng = rand::thread_rng();
+        for &y in rows.iter().filter(|&&y| y >= 0) {
+            for x in 0..columns {
+                let (px, py) = layout.cell(x as isize, y);
+                for _ in 0..PARTICLES_PER_CELL {
+                    self.particles.push(Particle {
+                        x: px + rng.gen_range(0.0, layout.box_size),
+                        y: py + rng.gen_range(0.0, layout.box_size),
+                        dx: rng.gen_range(-150.0, 150.0),
+                        dy: rng.gen_range(-350.0, -50.0),
+                        age: 0.0,
+                        life: rng.gen_range(0.4, 0.9),
+                    });
+                }
+            }
+        }
+    }
+
+    /// Moves `c` to where the board is drawn while it shakes.
+    pub fn shaken(&self, c: &Context) -> Context {
+        if self.shake <= 0.0 {
+            return *c;
+        }
+        let size = SHAKE_SIZE * self.shake / SHAKE_TIME;
+        let mut rng = rand::thread_rng();
+        c.trans(rng.gen_range(-size, size), rng.gen_range(-size, size))
+    }
+
+    pub fn draw(&self, layout: &Layout, c: &Context, g: &mut GlGraphics, glyphs: &mut GlyphCache) {
+        let theme = &layout.theme;
+        for particle in &self.particles {
+            let mut color = theme.text;
+            color[3] = (1.0 - particle.age / particle.life) as f32;
+            let size = layout.box_size / 5.0;
+            Rectangle::new(color).draw(
+                [particle.x, particle.y, size, size],
+                &c.draw_state,
+                c.transform,
+                g,
+            );
+        }
+        if let Some(popup) = &self.popup {
+            let progress = popup.age / POPUP_TIME;
+            // Hold for a moment, then fade out while rising.
+            let fade = ((1.0 - progress) * 3.0).min(1.0) as f32;
+            let y = layout.height * 0.4 - progress * POPUP_RISE;
+            let mut font = Text::new_color(theme.text, 28);
+            for (i, line) in popup.lines.iter().enumerate() {
+                let mut shadow = theme.text_shadow;
+                shadow[3] *= fade;
+                font.color = theme.text;
+                font.color[3] *= fade;
+                let transform = c.transform.trans(layout.width / 2.0, y + i as f64 * 30.0);
+                Text::new_color(shadow, font.font_size)
+                    .draw_center(line, glyphs, &c.draw_state, transform.trans(2.0, 2.0), g)
+                    .expect("Unable to draw string");
+                font.draw_center(line, glyphs, &c.draw_state, transform, g)
+                    .expect("Unable to draw string");
+                font.font_size = 20;
+            }
+        }
+    }
+}
+
+// Names the clear if it's worth naming, then any back-to-back and combo.
+fn clear_popup(lines: u32, combo: u32, t_spin: bool, back_to_back: bool) -> Vec<String> {
+    let name = match lines {
+        1 => "SINGLE".to_string(),
+        2 => "DOUBLE".to_string(),
+        3 => "TRIPLE".to_string(),
+        4 => "TETRIS".to_string(),
+        _ => format!("{} LINES", lines),
+    };
+    let mut popup = Vec::new();
+    if t_spin {
+        popup.push(format!("T-SPIN {}", name));
+    } else if lines >= 4 {
+        popup.push(name);
+    }
+    if back_to_back {
+        popup.push("Back-to-Back".to_string());
+    }
+    if combo > 0 {
+        popup.push(format!("{} Combo", combo));
+    }
+    popup
+}
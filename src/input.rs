@@ -0,0 +1,633 @@
+use crate::config::{self, ConfigError};
+use crate::{CenterText, Layout, State};
+use graphics::text::Text;
+use graphics::Transformed;
+use opengl_graphics::{GlGraphics, GlyphCache};
+use piston::input::{keyboard::Key, Button, GenericEvent};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::PathBuf;
+
+/// Everything a player can ask the game to do, independent of which key
+/// asks for it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    MoveLeft,
+    MoveRight,
+    SoftDrop,
+    HardDrop,
+    RotateCw,
+    RotateCcw,
+    Hold,
+    Pause,
+    Quit,
+    Fullscreen,
+}
+
+pub const ACTIONS: [Action; 10] = [
+    Action::MoveLeft,
+    Action::MoveRight,
+    Action::SoftDrop,
+    Action::HardDrop,
+    Action::RotateCw,
+    Action::RotateCcw,
+    Action::Hold,
+    Action::Pause,
+    Action::Quit,
+    Action::Fullscreen,
+];
+
+impl Action {
+    pub fn label(self) -> &'static str {
+        match self {
+            Action::MoveLeft => "Move left",
+            Action::MoveRight => "Move right",
+            Action::SoftDrop => "Soft drop",
+            Action::HardDrop => "Hard drop",
+            Action::RotateCw => "Rotate clockwise",
+            Action::RotateCcw => "Rotate counter-clockwise",
+            Action::Hold => "Hold",
+            Action::Pause => "Pause",
+            Action::Quit => "Quit",
+            Action::Fullscreen => "Toggle fullscreen",
+        }
+    }
+}
+
+/// Which keys trigger each action, as stored in `keys.toml`, along with the
+/// mappings for any game controllers.
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct KeyBindings {
+    move_left: Vec<Key>,
+    move_right: Vec<Key>,
+    soft_drop: Vec<Key>,
+    hard_drop: Vec<Key>,
+    rotate_cw: Vec<Key>,
+    rotate_ccw: Vec<Key>,
+    hold: Vec<Key>,
+    pause: Vec<Key>,
+    quit: Vec<Key>,
+    fullscreen: Vec<Key>,
+    gamepad: GamepadBindings,
+}
+
+impl Default for KeyBindings {
+    fn default() -> KeyBindings {
+        KeyBindings {
+            move_left: vec![Key::Left, Key::A],
+            move_right: vec![Key::Right, Key::D],
+            soft_drop: vec![Key::Down],
+            hard_drop: vec![Key::Space],
+            rotate_cw: vec![Key::Up, Key::W],
+            rotate_ccw: vec![Key::Q],
+            hold: vec![Key::C],
+            pause: vec![Key::P],
+            quit: vec![Key::Escape],
+            fullscreen: vec![Key::F11],
+            gamepad: GamepadBindings::default(),
+        }
+    }
+}
+
+impl KeyBindings {
+    fn path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("tetris").join("keys.toml"))
+    }
+
+    /// Reads the bindings file, falling back to the defaults when there is
+    /// none.
+    pub fn load() -> Result<KeyBindings, ConfigError> {
+        config::load_file(KeyBindings::path())
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        config::save_file(self, KeyBindings::path())
+    }
+
+    pub fn keys(&self, action: Action) -> &Vec<Key> {
+        match action {
+            Action::MoveLeft => &self.move_left,
+            Action::MoveRight => &self.move_right,
+            Action::SoftDrop => &self.soft_drop,
+            Action::HardDrop => &self.hard_drop,
+            Action::RotateCw => &self.rotate_cw,
+            Action::RotateCcw => &self.rotate_ccw,
+            Action::Hold => &self.hold,
+            Action::Pause => &self.pause,
+            Action::Quit => &self.quit,
+            Action::Fullscreen => &self.fullscreen,
+        }
+    }
+
+    fn keys_mut(&mut self, action: Action) -> &mut Vec<Key> {
+        match action {
+            Action::MoveLeft => &mut self.move_left,
+            Action::MoveRight => &mut self.move_right,
+            Action::SoftDrop => &mut self.soft_drop,
+            Action::HardDrop => &mut self.hard_drop,
+            Action::RotateCw => &mut self.rotate_cw,
+            Action::RotateCcw => &mut self.rotate_ccw,
+            Action::Hold => &mut self.hold,
+            Action::Pause => &mut self.pause,
+            Action::Quit => &mut self.quit,
+            Action::Fullscreen => &mut self.fullscreen,
+        }
+    }
+
+    pub fn action(&self, key: Key) -> Option<Action> {
+        ACTIONS
+            .iter()
+            .copied()
+            .find(|&action| self.keys(action).contains(&key))
+    }
+
+    /// Binds the key to the action alone, unbinding it from anything else.
+    fn bind(&mut self, action: Action, key: Key) {
+        for &other in ACTIONS.iter() {
+            self.keys_mut(other).retain(|&bound| bound != key);
+        }
+        self.keys_mut(action).push(key);
+    }
+}
+
+/// A controller button, named by where it sits on a standard gamepad.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum PadButton {
+    South,
+    East,
+    North,
+    West,
+    LeftTrigger,
+    LeftTrigger2,
+    RightTrigger,
+    RightTrigger2,
+    Select,
+    Start,
+    Mode,
+    LeftThumb,
+    RightThumb,
+    DPadUp,
+    DPadDown,
+    DPadLeft,
+    DPadRight,
+}
+
+/// An analog stick axis. Pushing right or up is positive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum PadAxis {
+    LeftStickX,
+    LeftStickY,
+    RightStickX,
+    RightStickY,
+}
+
+/// A button or analog stick direction on a controller.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum ControllerInput {
+    Button { button: PadButton },
+    Axis { axis: PadAxis, positive: bool },
+}
+
+/// The controller mapping, shared by every connected controller.
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct GamepadBindings {
+    // How far a stick has to be pushed before it counts as pressed.
+    axis_threshold: f64,
+    move_left: Vec<ControllerInput>,
+    move_right: Vec<ControllerInput>,
+    soft_drop: Vec<ControllerInput>,
+    hard_drop: Vec<ControllerInput>,
+    rotate_cw: Vec<ControllerInput>,
+    rotate_ccw: Vec<ControllerInput>,
+    hold: Vec<ControllerInput>,
+    pause: Vec<ControllerInput>,
+    quit: Vec<ControllerInput>,
+    fullscreen: Vec<ControllerInput>,
+}
+
+impl Default for GamepadBindings {
+    fn default() -> GamepadBindings {
+        use ControllerInput::*;
+        let button = |button| Button { button };
+        GamepadBindings {
+            axis_threshold: 0.5,
+            move_left: vec![
+                button(PadButton::DPadLeft),
+                Axis {
+                    axis: PadAxis::LeftStickX,
+                    positive: false,
+                },
+            ],
+            move_right: vec![
+                button(PadButton::DPadRight),
+                Axis {
+                    axis: PadAxis::LeftStickX,
+                    positive: true,
+                },
+            ],
+            soft_drop: vec![
+                button(PadButton::DPadDown),
+                Axis {
+                    axis: PadAxis::LeftStickY,
+                    positive: false,
+                },
+            ],
+            hard_drop: vec![button(PadButton::DPadUp)],
+            rotate_cw: vec![button(PadButton::South)],
+            rotate_ccw: vec![button(PadButton::East)],
+            hold: vec![
+                button(PadButton::LeftTrigger),
+                button(PadButton::RightTrigger),
+            ],
+            pause: vec![button(PadButton::Start)],
+            quit: vec![],
+            fullscreen: vec![],
+        }
+    }
+}
+
+// Controllers are only read with the gamepad feature.
+#[cfg_attr(not(feature = "gamepad"), allow(dead_code))]
+impl GamepadBindings {
+    fn inputs(&self, action: Action) -> &Vec<ControllerInput> {
+        match action {
+            Action::MoveLeft => &self.move_left,
+            Action::MoveRight => &self.move_right,
+            Action::SoftDrop => &self.soft_drop,
+            Action::HardDrop => &self.hard_drop,
+            Action::RotateCw => &self.rotate_cw,
+            Action::RotateCcw => &self.rotate_ccw,
+            Action::Hold => &self.hold,
+            Action::Pause => &self.pause,
+            Action::Quit => &self.quit,
+            Action::Fullscreen => &self.fullscreen,
+        }
+    }
+
+    fn action(&self, input: ControllerInput) -> Option<Action> {
+        ACTIONS
+            .iter()
+            .copied()
+            .find(|&action| self.inputs(action).contains(&input))
+    }
+}
+
+/// The actions started and stopped by a single input event.
+#[derive(Default)]
+pub struct ActionEvents {
+    pub pressed: Vec<Action>,
+    pub released: Vec<Action>,
+}
+
+/// Turns raw input events, and anything the controllers have reported
+/// since, into actions.
+///
+/// The window passes on the system's key repeat as more presses, so held
+/// keys are remembered in order to drop those. Sticks only report their
+/// current position, so the last direction of each is remembered in order
+/// to emit a press when one is pushed and a release when it returns.
+pub struct Controls {
+    pub bindings: KeyBindings,
+    held: HashSet<Key>,
+    #[cfg_attr(not(feature = "gamepad"), allow(dead_code))]
+    axes: HashMap<(usize, PadAxis), i8>,
+    #[cfg(feature = "gamepad")]
+    gilrs: Option<gilrs::Gilrs>,
+}
+
+impl Controls {
+    pub fn load() -> Result<Controls, ConfigError> {
+        Ok(Controls::new(KeyBindings::load()?))
+    }
+
+    fn new(bindings: KeyBindings) -> Controls {
+        Controls {
+            bindings,
+            held: HashSet::new(),
+            axes: HashMap::new(),
+            #[cfg(feature = "gamepad")]
+            gilrs: gilrs::Gilrs::new()
+                .map_err(|err| eprintln!("Controllers are unavailable: {}", err))
+                .ok(),
+        }
+    }
+
+    pub fn translate<E: GenericEvent>(&mut self, e: &E) -> ActionEvents {
+        let mut events = ActionEvents::default();
+        // Keys let go in another window never report a release.
+        if e.focus_args() == Some(false) {
+            self.held.clear();
+        }
+        if let Some(Button::Keyboard(key)) = e.press_args() {
+            if self.held.insert(key) {
+                events.pressed.extend(self.bindings.action(key));
+            }
+        }
+        if let Some(Button::Keyboard(key)) = e.release_args() {
+            self.held.remove(&key);
+            events.released.extend(self.bindings.action(key));
+        }
+        self.poll_controllers(&mut events);
+        events
+    }
+
+    #[cfg(feature = "gamepad")]
+    fn poll_controllers(&mut self, events: &mut ActionEvents) {
+        use gilrs::EventType;
+        let mut polled = Vec::new();
+        if let Some(gilrs) = &mut self.gilrs {
+            while let Some(event) = gilrs.next_event() {
+                polled.push(event);
+            }
+        }
+        for event in polled {
+            let id = usize::from(event.id);
+            match event.event {
+                EventType::ButtonPressed(button, _) => {
+                    if let Some(button) = pad_button(button) {
+                        self.button(button, true, events);
+                    }
+                }
+                EventType::ButtonReleased(button, _) => {
+                    if let Some(button) = pad_button(button) {
+                        self.button(button, false, events);
+                    }
+                }
+                EventType::AxisChanged(axis, position, _) => {
+                    if let Some(axis) = pad_axis(axis) {
+                        self.axis(id, axis, position as f64, events);
+                    }
+                }
+                // Let go of any stick left pushed.
+                EventType::Disconnected => {
+                    let axes: Vec<_> = self
+                        .axes
+                        .keys()
+                        .filter(|&&(pad, _)| pad == id)
+                        .copied()
+                        .collect();
+                    for (pad, axis) in axes {
+                        self.axis(pad, axis, 0.0, events);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    #[cfg(not(feature = "gamepad"))]
+    fn poll_controllers(&mut self, _events: &mut ActionEvents) {}
+
+    #[cfg_attr(not(feature = "gamepad"), allow(dead_code))]
+    fn button(&self, button: PadButton, pressed: bool, events: &mut ActionEvents) {
+        let action = self
+            .bindings
+            .gamepad
+            .action(ControllerInput::Button { button });
+        if pressed {
+            events.pressed.extend(action);
+        } else {
+            events.released.extend(action);
+        }
+    }
+
+    #[cfg_attr(not(feature = "gamepad"), allow(dead_code))]
+    fn axis(&mut self, pad: usize, axis: PadAxis, position: f64, events: &mut ActionEvents) {
+        let gamepad = &self.bindings.gamepad;
+        let direction = if position > gamepad.axis_threshold {
+            1
+        } else if position < -gamepad.axis_threshold {
+            -1
+        } else {
+            0
+        };
+        let previous = self.axes.insert((pad, axis), direction).unwrap_or(0);
+        let input = |direction: i8| ControllerInput::Axis {
+            axis,
+            positive: direction > 0,
+        };
+        if previous != direction {
+            if previous != 0 {
+                events.released.extend(gamepad.action(input(previous)));
+            }
+            if direction != 0 {
+                events.pressed.extend(gamepad.action(input(direction)));
+            }
+        }
+    }
+}
+
+#[cfg(feature = "gamepad")]
+fn pad_button(button: gilrs::Button) -> Option<PadButton> {
+    use gilrs::Button::*;
+    Some(match button {
+        South => PadButton::South,
+        East => PadButton::East,
+        North => PadButton::North,
+        West => PadButton::West,
+        LeftTrigger => PadButton::LeftTrigger,
+        LeftTrigger2 => PadButton::LeftTrigger2,
+        RightTrigger => PadButton::RightTrigger,
+        RightTrigger2 => PadButton::RightTrigger2,
+        Select => PadButton::Select,
+        Start => PadButton::Start,
+        Mode => PadButton::Mode,
+        LeftThumb => PadButton::LeftThumb,
+        RightThumb => PadButton::RightThumb,
+        DPadUp => PadButton::DPadUp,
+        DPadDown => PadButton::DPadDown,
+        DPadLeft => PadButton::DPadLeft,
+        DPadRight => PadButton::DPadRight,
+        C | Z | Unknown => return None,
+    })
+}
+
+#[cfg(feature = "gamepad")]
+fn pad_axis(axis: gilrs::Axis) -> Option<PadAxis> {
+    use gilrs::Axis::*;
+    Some(match axis {
+        LeftStickX => PadAxis::LeftStickX,
+        LeftStickY => PadAxis::LeftStickY,
+        RightStickX => PadAxis::RightStickX,
+        RightStickY => PadAxis::RightStickY,
+        _ => return None,
+    })
+}
+
+/// The in-game rebinding screen. The arrow keys, Enter, Backspace and Escape
+/// always work here so a bad binding can't lock the player out.
+#[derive(Default)]
+pub struct BindingsScreen {
+    selected: usize,
+    capturing: bool,
+}
+
+impl BindingsScreen {
+    pub fn run<E: GenericEvent>(
+        &mut self,
+        e: E,
+        bindings: &mut KeyBindings,
+        layout: &Layout,
+        gl: &mut GlGraphics,
+        glyphs: &mut GlyphCache,
+    ) -> State {
+        if let Some(Button::Keyboard(key)) = e.press_args() {
+            let action = ACTIONS[self.selected];
+            if self.capturing {
+                bindings.bind(action, key);
+                self.capturing = false;
+            } else {
+                match key {
+                    Key::Up => self.selected = (self.selected + ACTIONS.len() - 1) % ACTIONS.len(),
+                    Key::Down => self.selected = (self.selected + 1) % ACTIONS.len(),
+                    Key::Return => self.capturing = true,
+                    Key::Backspace => bindings.keys_mut(action).clear(),
+                    Key::Escape => {
+                        if let Err(err) = bindings.save() {
+                            eprintln!("Unable to save key bindings: {}", err);
+                        }
+                        return State::TitleScreen;
+                    }
+                    _ => {}
+                }
+            }
+        }
+        if let Some(args) = e.render_args() {
+            gl.draw(args.viewport(), |c, g| {
+                use graphics::clear;
+                let theme = &layout.theme;
+                clear(theme.background, g);
+                let c = layout.fit(c, args.window_size);
+                let mut font = Text::new(40);
+                font.color = theme.text_shadow;
+                font.draw_center(
+                    "Key Bindings",
+                    glyphs,
+                    &c.draw_state,
+                    c.transform.trans(layout.width / 2.0, 60.0),
+                    g,
+                )
+                .expect("Unable to draw string");
+                font.font_size = 18;
+                for (i, &action) in ACTIONS.iter().enumerate() {
+                    let keys = if self.capturing && i == self.selected {
+                        "Press a key...".to_string()
+                    } else {
+                        let names: Vec<_> = bindings
+                            .keys(action)
+                            .iter()
+                            .map(|key| format!("{:?}", key))
+                            .collect();
+                        names.join(", ")
+                    };
+                    font.color = if i == self.selected {
+                        theme.text
+                    } else {
+                        theme.text_shadow
+                    };
+                    let y = 110.0 + i as f64 * 28.0;
+                    font.draw(
+                        action.label(),
+                        glyphs,
+                        &c.draw_state,
+                        c.transform.trans(80.0, y),
+                        g,
+                    )
+                    .expect("Unable to draw string");
+                    font.draw(
+                        keys.as_str(),
+                        glyphs,
+                        &c.draw_state,
+                        c.transform.trans(360.0, y),
+                        g,
+                    )
+                    .expect("Unable to draw string");
+                }
+                font.color = theme.text;
+                font.draw_center(
+                    "Enter: add key  Backspace: clear  Esc: save and return",
+                    glyphs,
+                    &c.draw_state,
+                    c.transform.trans(layout.width / 2.0, layout.height - 30.0),
+                    g,
+                )
+                .expect("Unable to draw string");
+            });
+        }
+        State::KeyBindings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use piston::input::{ButtonArgs, ButtonState, Event, Input};
+
+    fn key(state: ButtonState, key: Key) -> Event {
+        let args = ButtonArgs {
+            state,
+            button: Button::Keyboard(key),
+            scancode: None,
+        };
+        Event::Input(Input::Button(args), None)
+    }
+
+    #[test]
+    fn ignores_key_repeat() {
+        let mut controls = Controls::new(KeyBindings::default());
+        let press = key(ButtonState::Press, Key::Left);
+        assert_eq!(controls.translate(&press).pressed, vec![Action::MoveLeft]);
+        // The system repeats a held key as more presses.
+        assert!(controls.translate(&press).pressed.is_empty());
+        assert!(controls.translate(&press).pressed.is_empty());
+        let release = key(ButtonState::Release, Key::Left);
+        assert_eq!(
+            controls.translate(&release).released,
+            vec![Action::MoveLeft]
+        );
+        assert_eq!(controls.translate(&press).pressed, vec![Action::MoveLeft]);
+    }
+
+    #[test]
+    fn forgets_held_keys_on_focus_loss() {
+        let mut controls = Controls::new(KeyBindings::default());
+        let press = key(ButtonState::Press, Key::Left);
+        controls.translate(&press);
+        controls.translate(&Event::Input(Input::Focus(false), None));
+        assert_eq!(controls.translate(&press).pressed, vec![Action::MoveLeft]);
+    }
+
+    #[test]
+    fn sticks_press_and_release_past_the_threshold() {
+        let mut controls = Controls::new(KeyBindings::default());
+        let mut events = ActionEvents::default();
+        controls.axis(0, PadAxis::LeftStickX, -0.3, &mut events);
+        assert!(events.pressed.is_empty());
+        controls.axis(0, PadAxis::LeftStickX, -0.8, &mut events);
+        controls.axis(0, PadAxis::LeftStickX, -0.9, &mut events);
+        assert_eq!(events.pressed, vec![Action::MoveLeft]);
+        // Swinging straight across lets go of one side and pushes the other.
+        let mut events = ActionEvents::default();
+        controls.axis(0, PadAxis::LeftStickX, 0.9, &mut events);
+        assert_eq!(events.released, vec![Action::MoveLeft]);
+        assert_eq!(events.pressed, vec![Action::MoveRight]);
+        let mut events = ActionEvents::default();
+        controls.axis(0, PadAxis::LeftStickY, -0.9, &mut events);
+        assert_eq!(events.pressed, vec![Action::SoftDrop]);
+    }
+
+    #[test]
+    fn maps_buttons() {
+        let controls = Controls::new(KeyBindings::default());
+        let mut events = ActionEvents::default();
+        controls.button(PadButton::South, true, &mut events);
+        controls.button(PadButton::DPadUp, false, &mut events);
+        controls.button(PadButton::Mode, true, &mut events);
+        assert_eq!(events.pressed, vec![Action::RotateCw]);
+        assert_eq!(events.released, vec![Action::HardDrop]);
+    }
+}
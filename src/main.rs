@@ -6,382 +6,822 @@ use graphics::types::Color;
 use graphics::{Context, DrawState, Graphics, Transformed};
 use opengl_graphics::{Filter, GlGraphics, GlyphCache, OpenGL, TextureSettings};
 use piston::event_loop::{EventLoop, EventSettings, Events};
-use piston::input::{keyboard::Key, Button, GenericEvent};
+use piston::input::{CloseEvent, GenericEvent, UpdateEvent};
 use piston::window::WindowSettings;
-use rand::{rngs::ThreadRng, Rng};
+use rand::{rngs::StdRng, Rng, SeedableRng};
 use std::clone::Clone;
 use std::time::{Duration, Instant};
 
-const FPS: u64 = 25;
-const WIDTH: u32 = 640;
-const HEIGHT: u32 = 480;
-const BOXSIZE: u32 = 20;
-const BOARDWIDTH: usize = 10;
-const BOARDHEIGHT: usize = 20;
+mod audio;
+mod bot;
+mod config;
+mod effects;
+mod input;
+mod menu;
+mod pathfind;
+mod scores;
+mod shapes;
+mod skin;
+mod stats;
+mod theme;
+
+use audio::{Audio, Sound};
+use config::Settings;
+use effects::Effects;
+use input::{Action, ActionEvents, BindingsScreen, Controls};
+use menu::{Choice, Confirm, MainMenu, PauseChoice, PauseMenu, SettingsMenu};
+use pathfind::Placement;
+use scores::{HighScoreScreen, HighScores, NameEntry, TableKey};
+use serde::{Deserialize, Serialize};
+use shapes::{Shape, ShapeSet};
+use skin::{BlockPainter, Skin};
+use stats::{Stats, StatsScreen};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use theme::Theme;
+
 const BLANK: u8 = b'.';
 const TEMPLATEWIDTH: usize = 5;
 const TEMPLATEHEIGHT: usize = 5;
 
-#[cfg_attr(rustfmt, rustfmt_skip)]
-const S_SHAPE: [[&'static [u8; TEMPLATEWIDTH]; TEMPLATEHEIGHT]; 2] = [
-    [b".....",
-     b".....",
-     b"..OO.",
-     b".OO..",
-     b"....."],
-    [b".....",
-     b"..O..",
-     b"..OO.",
-     b"...O.",
-     b"....."],
-];
-#[cfg_attr(rustfmt, rustfmt_skip)]
-const Z_SHAPE: [[&'static [u8; TEMPLATEWIDTH]; TEMPLATEHEIGHT]; 2] = [
-    [b".....",
-     b".....",
-     b".OO..",
-     b"..OO.",
-     b"....."],
-    [b".....",
-     b"..O..",
-     b".OO..",
-     b".O...",
-     b"....."],
-];
-#[cfg_attr(rustfmt, rustfmt_skip)]
-const I_SHAPE: [[&'static [u8; TEMPLATEWIDTH]; TEMPLATEHEIGHT]; 2] = [
-    [b"..O..",
-     b"..O..",
-     b"..O..",
-     b"..O..",
-     b"....."],
-    [b".....",
-     b".....",
-     b"OOOO.",
-     b".....",
-     b"....."],
-];
-#[cfg_attr(rustfmt, rustfmt_skip)]
-const O_SHAPE: [[&'static [u8; TEMPLATEWIDTH]; TEMPLATEHEIGHT]; 1] = [
-    [b".....",
-     b".....",
-     b".OO..",
-     b".OO..",
-     b"....."],
-];
-#[cfg_attr(rustfmt, rustfmt_skip)]
-const J_SHAPE: [[&'static [u8; TEMPLATEWIDTH]; TEMPLATEHEIGHT]; 4] = [
-    [b".....",
-     b".O...",
-     b".OOO.",
-     b".....",
-     b"....."],
-    [b".....",
-     b".OO..",
-     b".O...",
-     b".O...",
-     b"....."],
-    [b".....",
-     b".....",
-     b".OOO.",
-     b"...O.",
-     b"....."],
-    [b".....",
-     b"..O..",
-     b"..O..",
-     b".OO..",
-     b"....."],
-];
-#[cfg_attr(rustfmt, rustfmt_skip)]
-const L_SHAPE: [[&'static [u8; TEMPLATEWIDTH]; TEMPLATEHEIGHT]; 4] = [
-    [b".....",
-     b"...O.",
-     b".OOO.",
-     b".....",
-     b"....."],
-    [b".....",
-     b"..O..",
-     b"..O..",
-     b"..OO.",
-     b"....."],
-    [b".....",
-     b".....",
-     b".OOO.",
-     b".O...",
-     b"....."],
-    [b".....",
-     b".OO..",
-     b"..O..",
-     b"..O..",
-     b"....."],
-];
-#[cfg_attr(rustfmt, rustfmt_skip)]
-const T_SHAPE: [[&'static [u8; TEMPLATEWIDTH]; TEMPLATEHEIGHT]; 4] = [
-    [b".....",
-     b"..O..",
-     b".OOO.",
-     b".....",
-     b"....."],
-    [b".....",
-     b"..O..",
-     b"..OO.",
-     b"..O..",
-     b"....."],
-    [b".....",
-     b".....",
-     b".OOO.",
-     b"..O..",
-     b"....."],
-    [b".....",
-     b"..O..",
-     b".OO..",
-     b"..O..",
-     b"....."],
-];
-
-const MOVESIDEWAYSFREQ: Duration = Duration::from_millis(150);
-const MOVEDOWNFREQ: Duration = Duration::from_millis(100);
-
-const XMARGIN: u32 = (WIDTH - BOARDWIDTH as u32 * BOXSIZE) / 2;
-const TOPMARGIN: u32 = HEIGHT - (BOARDHEIGHT as u32 * BOXSIZE) - 5;
+/// Offsets tried in order when a rotation is blocked.
+const KICKS: [(isize, isize); 4] = [(0, 0), (-1, 0), (1, 0), (0, -1)];
 
 const WHITE: Color = [1.0, 1.0, 1.0, 1.0];
 const GRAY: Color = [0.72, 0.72, 0.72, 1.0];
 const BLACK: Color = [0.0, 0.0, 0.0, 1.0];
-const RED: Color = [0.60, 0.60, 0.60, 1.0];
-const LIGHTRED: Color = [0.68, 0.68, 0.68, 1.0];
+const MIDGRAY: Color = [0.60, 0.60, 0.60, 1.0];
+const DARKGRAY: Color = [0.30, 0.30, 0.30, 1.0];
+const LIGHTMIDGRAY: Color = [0.68, 0.68, 0.68, 1.0];
 const GREEN: Color = [0.0, 0.60, 0.0, 1.0];
 const LIGHTGREEN: Color = [0.1, 0.68, 0.0, 1.0];
 const BLUE: Color = [0.0, 0.0, 0.60, 1.0];
 const LIGHTBLUE: Color = [0.1, 0.1, 0.68, 1.0];
 const YELLOW: Color = [0.60, 0.68, 0.0, 1.0];
 const LIGHTYELLOW: Color = [0.68, 0.68, 0.0, 1.0];
+const RED: Color = [0.85, 0.0, 0.0, 1.0];
 
+// The classic look, which themes start from.
 const BORDERCOLOR: Color = BLUE;
 const BGCOLOR: Color = BLACK;
 const TEXTCOLOR: Color = WHITE;
 const TEXTSHADOWCOLOR: Color = GRAY;
-const COLORS: [Color; 4] = [BLUE, GREEN, RED, YELLOW];
-const LIGHTCOLORS: [Color; 4] = [LIGHTBLUE, LIGHTGREEN, LIGHTRED, LIGHTYELLOW];
+const DANGERCOLOR: Color = RED;
+const COLORS: [Color; 4] = [BLUE, GREEN, MIDGRAY, YELLOW];
+const LIGHTCOLORS: [Color; 4] = [LIGHTBLUE, LIGHTGREEN, LIGHTMIDGRAY, LIGHTYELLOW];
 
-#[derive(Clone)]
-enum Shape {
-    S,
-    Z,
-    J,
-    L,
-    I,
-    O,
-    T,
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    TitleScreen,
+    SettingsScreen,
+    HighScores,
+    Run,
+    Paused,
+    GameOver,
+    KeyBindings,
+    EnterName,
+    Statistics,
+    ConfirmQuit,
+    Quit,
 }
 
-impl Shape {
-    fn template(&self) -> Vec<[&'static [u8; TEMPLATEWIDTH]; TEMPLATEHEIGHT]> {
+#[derive(Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Mode {
+    Marathon,
+    FinesseTraining,
+    Pentomino,
+}
+
+const MODES: [Mode; 3] = [Mode::Marathon, Mode::FinesseTraining, Mode::Pentomino];
+
+impl Mode {
+    fn label(self) -> &'static str {
         match self {
-            Shape::S => S_SHAPE.to_vec(),
-            Shape::Z => Z_SHAPE.to_vec(),
-            Shape::J => J_SHAPE.to_vec(),
-            Shape::L => L_SHAPE.to_vec(),
-            Shape::I => I_SHAPE.to_vec(),
-            Shape::O => O_SHAPE.to_vec(),
-            Shape::T => T_SHAPE.to_vec(),
+            Mode::Marathon => "Marathon",
+            Mode::FinesseTraining => "Finesse training",
+            Mode::Pentomino => "Pentominoes",
         }
     }
 }
 
-#[derive(PartialEq)]
-enum State {
-    TitleScreen,
-    Run,
-    Paused,
-    GameOver,
-    Quit,
+struct Args {
+    mode: Option<Mode>,
+    bot: Option<String>,
+    config: Option<PathBuf>,
+}
+
+impl Args {
+    fn parse() -> Args {
+        let mut args = Args {
+            mode: None,
+            bot: None,
+            config: None,
+        };
+        let mut argv = std::env::args().skip(1);
+        while let Some(arg) = argv.next() {
+            match arg.as_str() {
+                "--finesse-training" => args.mode = Some(Mode::FinesseTraining),
+                "--pentomino" => args.mode = Some(Mode::Pentomino),
+                "--bot" => match argv.next() {
+                    Some(command) => args.bot = Some(command),
+                    None => Args::usage("--bot needs a command to run"),
+                },
+                "--config" => match argv.next() {
+                    Some(path) => args.config = Some(PathBuf::from(path)),
+                    None => Args::usage("--config needs a path"),
+                },
+                _ => Args::usage(&format!("Unknown argument: {}", arg)),
+            }
+        }
+        args
+    }
+
+    fn usage(error: &str) -> ! {
+        eprintln!("{}", error);
+        eprintln!(
+            "Usage: tetris [--config <path>] [--finesse-training | --pentomino] [--bot <command>]"
+        );
+        std::process::exit(2);
+    }
+}
+
+/// Where things go on screen for the current display settings, and the
+/// colors they are drawn in.
+#[derive(Clone)]
+struct Layout {
+    width: f64,
+    height: f64,
+    box_size: f64,
+    x_margin: f64,
+    top_margin: f64,
+    theme: Rc<Theme>,
+}
+
+impl Layout {
+    fn new(display: &config::Display, board: &Board, theme: &Rc<Theme>) -> Layout {
+        let width = display.width as f64;
+        let height = display.height as f64;
+        // Shrink the boxes when the board and side panels would not fit.
+        let fit_width = width / (board.width + 2 * (TEMPLATEWIDTH + 2)) as f64;
+        let fit_height = (height - 12.0) / board.height as f64;
+        let box_size = (display.box_size as f64)
+            .min(fit_width)
+            .min(fit_height)
+            .floor();
+        Layout {
+            width,
+            height,
+            box_size,
+            x_margin: ((width - board.width as f64 * box_size) / 2.0).floor(),
+            top_margin: height - (board.height as f64 * box_size) - 5.0,
+            theme: theme.clone(),
+        }
+    }
+
+    /// Scales drawing in layout coordinates up or down to fill a window of
+    /// the given size, keeping the aspect ratio and centering the result.
+    /// Window sizes are in points, so high-DPI screens need nothing extra.
+    fn fit(&self, c: Context, window_size: [f64; 2]) -> Context {
+        let [width, height] = window_size;
+        let scale = (width / self.width).min(height / self.height);
+        c.trans(
+            (width - self.width * scale) / 2.0,
+            (height - self.height * scale) / 2.0,
+        )
+        .scale(scale, scale)
+    }
+
+    fn cell(&self, x: isize, y: isize) -> (f64, f64) {
+        (
+            self.x_margin + (x as f64 * self.box_size),
+            self.top_margin + (y as f64 * self.box_size),
+        )
+    }
+}
+
+/// What games are built from, loaded from the settings.
+struct Resources {
+    theme: Rc<Theme>,
+    skin: Rc<Skin>,
+    shapes: ShapeSet,
+    pentominoes: ShapeSet,
+    layout: Layout,
+}
+
+impl Resources {
+    fn load(config: &Settings) -> Result<Resources, config::ConfigError> {
+        let mut theme = theme::load(&config.display.theme)?;
+        if config.assist.high_contrast {
+            theme = theme.high_contrast();
+        }
+        let theme = Rc::new(theme);
+        let mut shapes = shapes::load(config.game.shapes.as_deref())?.with_theme(&theme);
+        let mut pentominoes = shapes::pentominoes().with_theme(&theme);
+        if config.assist.high_contrast {
+            shapes = shapes.with_high_contrast();
+            pentominoes = pentominoes.with_high_contrast();
+        }
+        let layout = Layout::new(&config.display, &Board::new(&config.board), &theme);
+        let skin = Rc::new(skin::load(&config.display.skin)?);
+        Ok(Resources {
+            theme,
+            skin,
+            shapes,
+            pentominoes,
+            layout,
+        })
+    }
+
+    fn shapes(&self, mode: Mode) -> &ShapeSet {
+        match mode {
+            Mode::Pentomino => &self.pentominoes,
+            _ => &self.shapes,
+        }
+    }
+
+    fn new_game(&self, mode: Mode, config: &Settings) -> Tetris {
+        Tetris::new(mode, config, self.shapes(mode), &self.theme, &self.skin)
+    }
+
+    /// The high score table each mode would play on under `config`.
+    fn table_keys(&self, config: &Settings) -> Vec<TableKey> {
+        MODES
+            .iter()
+            .map(|&mode| TableKey::new(mode, config, self.shapes(mode)))
+            .collect()
+    }
 }
 
 fn main() {
-    assert!(
-        COLORS.len() == LIGHTCOLORS.len(),
-        "Each color must have a light color!"
-    );
+    let args = Args::parse();
+    let mut config = Settings::load(args.config.as_deref()).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    });
     let mut settings = EventSettings::new();
-    settings.set_lazy(true);
+    // Line clears, the entry delay and effects move on with the clock, so
+    // frames have to keep coming without any input.
+    settings.set_lazy(false);
     settings.swap_buffers(true);
-    settings.max_fps(FPS);
-    settings.ups(FPS);
+    settings.max_fps(config.display.fps);
+    settings.ups(config.display.fps);
     let mut events = Events::new(settings);
     let opengl = OpenGL::V3_2;
-    let settings = WindowSettings::new("Tetris", [WIDTH, HEIGHT])
-        .exit_on_esc(true)
+    let settings = WindowSettings::new("Tetris", [config.display.width, config.display.height])
+        .exit_on_esc(false)
+        .resizable(true)
+        .fullscreen(config.display.fullscreen)
         .graphics_api(opengl);
     let mut window: GlutinWindow = settings.build().expect("Could not create window");
     let mut gl = GlGraphics::new(opengl);
+    // Sprite sheets can only be loaded once there is a GL context.
+    let mut resources = Resources::load(&config).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    });
 
-    let mut tetris = Tetris::new();
+    let mut audio = Audio::new(&config.audio);
+    let mut controls = Controls::load().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    });
+    let mut bindings_screen = BindingsScreen::default();
+    let mut main_menu = MainMenu::default();
+    let mut settings_menu = SettingsMenu::new(&config);
+    // Settings are reachable from the main menu and the pause menu.
+    let mut settings_return = State::TitleScreen;
+    let mut pause_menu = PauseMenu::default();
+    // Quitting mid-game asks first, then goes back where it was asked from.
+    let mut confirm_quit = Confirm::new("Quit?", "Quit", "Keep playing");
+    let mut quit_return = State::Run;
+    let mut high_scores = HighScores::load().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    });
+    let mut high_score_screen =
+        HighScoreScreen::new(config.game.mode, resources.table_keys(&config));
+    // A qualifying score waits here while the player types their name.
+    let mut name_entry = NameEntry::new("", 0);
+    let mut pending_score = None;
+    let mut high_score_place = None;
+    let mut lifetime = Stats::load().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    });
+    // Shown after each game, and from the main menu without one.
+    let mut stats_screen = StatsScreen::new("Statistics", Vec::new(), None, &lifetime);
+    let mut tetris = resources.new_game(args.mode.unwrap_or(config.game.mode), &config);
+    let mut bot = args.bot.map(|command| {
+        bot::Bot::spawn(&command).unwrap_or_else(|err| {
+            eprintln!("Could not start bot {:?}: {}", command, err);
+            std::process::exit(1);
+        })
+    });
 
-    let texture_settings = TextureSettings::new().filter(Filter::Nearest);
-    let ref mut glyphs =
-        GlyphCache::new("freesansbold.ttf", (), texture_settings).expect("Could not load font");
-    let mut state = State::TitleScreen;
+    // Text is scaled with the window, so filter it smoothly.
+    let texture_settings = TextureSettings::new().filter(Filter::Linear);
+    let glyphs = &mut GlyphCache::new("freesansbold.ttf", (), texture_settings)
+        .expect("Could not load font");
+    // A mode on the command line skips the menu.
+    let mut state = if args.mode.is_some() {
+        State::Run
+    } else {
+        State::TitleScreen
+    };
+    let mut fullscreen = config.display.fullscreen;
     while let Some(e) = events.next(&mut window) {
         use State::*;
+        // Closing the window leaves the state alone, so the shutdown below
+        // still knows which screen had unsaved changes.
+        if e.close_args().is_some() {
+            break;
+        }
+        let actions = controls.translate(&e);
+        if state != KeyBindings && actions.pressed.contains(&Action::Fullscreen) {
+            fullscreen = !fullscreen;
+            let glutin = window.ctx.window();
+            glutin.set_fullscreen(if fullscreen {
+                Some(glutin.get_current_monitor())
+            } else {
+                None
+            });
+        }
         state = match state {
-            TitleScreen => {
-                show_text_screen("Tetris", e, &mut gl, glyphs, Run).unwrap_or(TitleScreen)
+            TitleScreen => match main_menu.run(&e, &actions, &resources.layout, &mut gl, glyphs) {
+                Some(Choice::Play(mode)) => {
+                    tetris = resources.new_game(mode, &config);
+                    Run
+                }
+                Some(Choice::Settings) => {
+                    settings_menu = SettingsMenu::new(&config);
+                    settings_return = TitleScreen;
+                    SettingsScreen
+                }
+                Some(Choice::KeyBindings) => {
+                    bindings_screen = BindingsScreen::default();
+                    KeyBindings
+                }
+                Some(Choice::HighScores) => {
+                    high_score_screen =
+                        HighScoreScreen::new(tetris.mode, resources.table_keys(&config));
+                    HighScores
+                }
+                Some(Choice::Statistics) => {
+                    stats_screen = StatsScreen::new("Statistics", Vec::new(), None, &lifetime);
+                    Statistics
+                }
+                None => TitleScreen,
+                Some(Choice::Quit) => Quit,
+            },
+            SettingsScreen => {
+                let layout = &resources.layout;
+                if settings_menu.run(&e, &actions, &mut config, layout, &mut gl, glyphs) {
+                    if let Err(err) = config.save(args.config.as_deref()) {
+                        eprintln!("Unable to save settings: {}", err);
+                    }
+                    match Resources::load(&config) {
+                        Ok(loaded) => resources = loaded,
+                        Err(err) => eprintln!("{}", err),
+                    }
+                    // Handling can change mid-game. Everything else waits for
+                    // the next one.
+                    tetris.settings.handling = config.handling.clone();
+                    tetris.effects.set_settings(&config.effects);
+                    audio.set_settings(&config.audio);
+                    settings_return
+                } else {
+                    SettingsScreen
+                }
+            }
+            HighScores => {
+                let layout = &resources.layout;
+                if high_score_screen.run(&e, &actions, &high_scores, layout, &mut gl, glyphs) {
+                    TitleScreen
+                } else {
+                    HighScores
+                }
+            }
+            Run => {
+                if let (Some(bot), Some(_)) = (bot.as_mut(), e.update_args()) {
+                    bot.update(&mut tetris);
+                }
+                let next_state = tetris.run(e, &actions, &mut gl, glyphs);
+                audio.play_events(&tetris.events);
+                match next_state {
+                    Paused => {
+                        pause_menu = PauseMenu::default();
+                        Paused
+                    }
+                    ConfirmQuit => {
+                        confirm_quit = Confirm::new("Quit?", "Quit", "Keep playing");
+                        quit_return = Run;
+                        ConfirmQuit
+                    }
+                    GameOver => {
+                        audio.play(Sound::GameOver);
+                        audio.stop_music();
+                        high_score_place = None;
+                        record_game(&mut lifetime, &tetris, bot.is_some());
+                        // Games played by a bot don't go on the table.
+                        if bot.is_none() && high_scores.qualifies(&tetris.table_key(), tetris.score)
+                        {
+                            pending_score = Some(tetris.score_entry(String::new()));
+                            name_entry = NameEntry::new(&high_scores.last_name, tetris.score);
+                            EnterName
+                        } else {
+                            stats_screen = game_over_screen(&tetris, None, &lifetime);
+                            GameOver
+                        }
+                    }
+                    next_state => next_state,
+                }
             }
-            Run => tetris.run(e, &mut gl, glyphs),
-            Paused => show_text_screen("Paused", e, &mut gl, glyphs, Run).unwrap_or(Paused),
-            GameOver => {
-                let next_state =
-                    show_text_screen("Game Over", e, &mut gl, glyphs, Run).unwrap_or(GameOver);
-                if next_state == Run {
-                    tetris = Tetris::new();
+            EnterName => match name_entry.run(&e, &actions, &resources.layout, &mut gl, glyphs) {
+                Some(keep) => {
+                    if keep {
+                        high_score_place = save_high_score(
+                            &mut high_scores,
+                            pending_score.take(),
+                            &name_entry.name,
+                            &tetris,
+                        );
+                    }
+                    pending_score = None;
+                    stats_screen = game_over_screen(&tetris, high_score_place, &lifetime);
+                    GameOver
+                }
+                None => EnterName,
+            },
+            Paused => {
+                let layout = &resources.layout;
+                let choice = if actions.pressed.contains(&Action::Pause) {
+                    Some(PauseChoice::Resume)
+                } else {
+                    pause_menu.run(&e, &actions, layout, &mut gl, glyphs)
+                };
+                match choice {
+                    Some(PauseChoice::Resume) => {
+                        tetris.resume();
+                        Run
+                    }
+                    Some(PauseChoice::Restart) => {
+                        record_game(&mut lifetime, &tetris, bot.is_some());
+                        audio.stop_music();
+                        tetris = resources.new_game(tetris.mode, &config);
+                        Run
+                    }
+                    Some(PauseChoice::Settings) => {
+                        settings_menu = SettingsMenu::new(&config);
+                        settings_return = Paused;
+                        SettingsScreen
+                    }
+                    Some(PauseChoice::QuitToMenu) => {
+                        record_game(&mut lifetime, &tetris, bot.is_some());
+                        audio.stop_music();
+                        TitleScreen
+                    }
+                    Some(PauseChoice::QuitGame) => {
+                        confirm_quit = Confirm::new("Quit?", "Quit", "Keep playing");
+                        quit_return = Paused;
+                        ConfirmQuit
+                    }
+                    None => Paused,
+                }
+            }
+            GameOver => match stats_screen.run(&e, &actions, &resources.layout, &mut gl, glyphs) {
+                Some(true) => {
+                    tetris = resources.new_game(tetris.mode, &config);
+                    Run
+                }
+                Some(false) => TitleScreen,
+                None => GameOver,
+            },
+            Statistics => {
+                match stats_screen.run(&e, &actions, &resources.layout, &mut gl, glyphs) {
+                    Some(_) => TitleScreen,
+                    None => Statistics,
+                }
+            }
+            KeyBindings => {
+                let layout = &resources.layout;
+                bindings_screen.run(e, &mut controls.bindings, layout, &mut gl, glyphs)
+            }
+            ConfirmQuit => {
+                let layout = &resources.layout;
+                match confirm_quit.run(&e, &actions, layout, &mut gl, glyphs) {
+                    Some(true) => {
+                        record_game(&mut lifetime, &tetris, bot.is_some());
+                        Quit
+                    }
+                    Some(false) => {
+                        if quit_return == Run {
+                            tetris.resume();
+                        }
+                        quit_return
+                    }
+                    None => ConfirmQuit,
                 }
-                next_state
             }
             Quit => break,
+        };
+        audio.update_music(state == State::Run, tetris.level);
+    }
+    if state == State::EnterName {
+        save_high_score(&mut high_scores, pending_score, &name_entry.name, &tetris);
+    }
+    // The game is unfinished if it's on screen or behind a menu opened from
+    // the pause menu.
+    let in_game = match state {
+        State::Run | State::Paused | State::ConfirmQuit => true,
+        State::SettingsScreen => settings_return == State::Paused,
+        _ => false,
+    };
+    if in_game {
+        record_game(&mut lifetime, &tetris, bot.is_some());
+    }
+    shutdown(state, &config, args.config.as_deref(), &controls);
+}
+
+/// Adds a game to the lifetime stats and saves them. Bot games and games
+/// left before the first piece locked don't count.
+fn record_game(lifetime: &mut Stats, tetris: &Tetris, by_bot: bool) {
+    let game = tetris.game_stats();
+    if by_bot || game.pieces == 0 {
+        return;
+    }
+    lifetime.add(&game);
+    if let Err(err) = lifetime.save() {
+        eprintln!("Unable to save stats: {}", err);
+    }
+}
+
+fn game_over_screen(
+    tetris: &Tetris,
+    high_score_place: Option<usize>,
+    lifetime: &Stats,
+) -> StatsScreen {
+    let mut details: Vec<String> = tetris
+        .top_out
+        .map(|top_out| top_out.description().to_string())
+        .into_iter()
+        .collect();
+    details.push(tetris.finesse_summary());
+    if let Some(place) = high_score_place {
+        details.push(format!("High score #{}", place + 1));
+    }
+    StatsScreen::new("Game Over", details, Some(tetris.game_stats()), lifetime)
+}
+
+/// Puts a finished game on the table under the given name and saves the
+/// table. Returns the game's place.
+fn save_high_score(
+    high_scores: &mut HighScores,
+    entry: Option<scores::Entry>,
+    name: &str,
+    tetris: &Tetris,
+) -> Option<usize> {
+    let mut entry = entry?;
+    entry.name = match name.trim() {
+        "" => "Player".to_string(),
+        name => name.to_string(),
+    };
+    let place = high_scores.insert(&tetris.table_key(), entry);
+    if let Err(err) = high_scores.save() {
+        eprintln!("Unable to save high scores: {}", err);
+    }
+    place
+}
+
+/// Writes out anything still unsaved. Quitting from the menus leaves
+/// nothing behind, but closing the window can happen on any screen.
+fn shutdown(state: State, config: &Settings, config_path: Option<&Path>, controls: &Controls) {
+    match state {
+        State::SettingsScreen => {
+            if let Err(err) = config.save(config_path) {
+                eprintln!("Unable to save settings: {}", err);
+            }
+        }
+        State::KeyBindings => {
+            if let Err(err) = controls.bindings.save() {
+                eprintln!("Unable to save key bindings: {}", err);
+            }
         }
+        _ => {}
     }
 }
 
+#[derive(Clone)]
 struct Piece {
-    shape: Shape,
+    shape: Rc<Shape>,
     rotation: usize,
-    x: usize,
+    x: isize,
     y: isize,
     color: usize,
 }
 
-impl DrawBox for Piece {}
-
 impl Piece {
-    fn new(rng: &mut ThreadRng) -> Piece {
-        use Shape::*;
-        let shapes = [I, J, S, Z, O, T, L];
+    fn new(shapes: &[Rc<Shape>], rng: &mut StdRng, random_rotation: bool) -> Piece {
         let shape = &shapes[rng.gen_range(0, shapes.len())];
         Piece {
             shape: shape.clone(),
-            rotation: rng.gen_range(0, shape.template().len()),
-            x: BOARDWIDTH / 2 - TEMPLATEWIDTH / 2,
-            y: -2,
-            color: rng.gen_range(0, COLORS.len()),
+            rotation: if random_rotation {
+                rng.gen_range(0, shape.template().len())
+            } else {
+                0
+            },
+            x: 0,
+            y: 0,
+            color: shape.colors[rng.gen_range(0, shape.colors.len())],
         }
     }
 
-    fn draw(&self, c: &Context, g: &mut GlGraphics) {
-        let (px, py) = xy_to_pxy(self.x, self.y as usize);
-        self.draw_at(px, py, c, g);
+    fn rotations(&self) -> usize {
+        self.shape.template().len()
     }
 
-    fn draw_at(&self, px: u32, py: u32, c: &Context, g: &mut GlGraphics) {
-        let to_draw = self.shape.template()[self.rotation];
-        for x in 0..TEMPLATEWIDTH {
-            for y in 0..TEMPLATEHEIGHT {
-                if to_draw[y][x] != BLANK {
-                    self.draw_box(
-                        px + (x as u32 * BOXSIZE),
-                        py + (y as u32 * BOXSIZE),
-                        Some(self.color),
-                        &c,
-                        g,
-                    );
+    /// The board coordinates of the piece's filled cells.
+    fn cells(&self) -> Vec<(isize, isize)> {
+        let mut cells = Vec::new();
+        for (y, row) in self.shape.template()[self.rotation].iter().enumerate() {
+            for (x, cell) in row.iter().enumerate() {
+                if *cell != BLANK {
+                    cells.push((self.x + x as isize, self.y + y as isize));
                 }
             }
         }
+        cells
     }
-}
 
-fn xy_to_pxy(x: usize, y: usize) -> (u32, u32) {
-    (
-        XMARGIN + (x as u32 * BOXSIZE),
-        TOPMARGIN + (y as u32 * BOXSIZE),
-    )
-}
+    /// The piece's blocks and the board cells they go in.
+    fn blocks(&self) -> Vec<(isize, isize, Block)> {
+        let cells = self.cells();
+        cells
+            .iter()
+            .map(|&(x, y)| {
+                let mut links = 0;
+                for &(link, dx, dy) in LINKS.iter() {
+                    if cells.contains(&(x + dx, y + dy)) {
+                        links |= link;
+                    }
+                }
+                let block = Block {
+                    color: self.color,
+                    shape: Some(self.shape.index),
+                    links,
+                };
+                (x, y, block)
+            })
+            .collect()
+    }
 
-trait DrawBox {
-    fn draw_box(&self, px: u32, py: u32, color: Option<usize>, c: &Context, g: &mut GlGraphics) {
-        use graphics::Rectangle;
-        match color {
-            None => {}
-            Some(color) => {
-                let border_rect = [
-                    px as f64 + 1.0f64,
-                    py as f64 + 1.0f64,
-                    BOXSIZE as f64 - 1.0f64,
-                    BOXSIZE as f64 - 1.0f64,
-                ];
-                let box_rect = [
-                    px as f64 + 1.0f64,
-                    py as f64 + 1.0f64,
-                    BOXSIZE as f64 - 4.0f64,
-                    BOXSIZE as f64 - 4.0f64,
-                ];
-
-                Rectangle::new(COLORS[color]).draw(border_rect, &c.draw_state, c.transform, g);
-                Rectangle::new(LIGHTCOLORS[color]).draw(box_rect, &c.draw_state, c.transform, g);
+    fn draw(&self, layout: &Layout, painter: &BlockPainter, c: &Context, g: &mut GlGraphics) {
+        let (px, py) = layout.cell(self.x, self.y);
+        self.draw_at(px, py, layout, painter, c, g);
+    }
+
+    fn draw_at(
+        &self,
+        px: f64,
+        py: f64,
+        layout: &Layout,
+        painter: &BlockPainter,
+        c: &Context,
+        g: &mut GlGraphics,
+    ) {
+        for (x, y, block) in self.blocks() {
+            let bx = px + ((x - self.x) as f64 * layout.box_size);
+            let by = py + ((y - self.y) as f64 * layout.box_size);
+            // Rows still above the board have nowhere to be drawn.
+            if by >= layout.top_margin {
+                painter.draw(block, bx, by, layout.box_size, c, g);
             }
         }
     }
 }
 
-struct Board([[Option<usize>; BOARDHEIGHT]; BOARDWIDTH]);
+/// What fills a board cell.
+#[derive(Clone, Copy)]
+struct Block {
+    color: usize,
+    /// Index of the piece's shape in its set, or `None` for garbage.
+    shape: Option<usize>,
+    /// Which neighbours are part of the same piece, for connected textures.
+    links: u8,
+}
+
+const LINK_UP: u8 = 1;
+const LINK_DOWN: u8 = 4;
+const LINKS: [(u8, isize, isize); 4] = [(LINK_UP, 0, -1), (2, 1, 0), (LINK_DOWN, 0, 1), (8, -1, 0)];
 
-impl DrawBox for Board {}
+/// The playfield. Rows `0..height` are visible; the `buffer` rows above
+/// them have negative y and hold pieces that stick out over the top.
+struct Board {
+    width: usize,
+    height: usize,
+    buffer: usize,
+    // Indexed by column, then row, starting from the top of the buffer.
+    cells: Vec<Vec<Option<Block>>>,
+}
 
 impl Board {
-    fn new() -> Board {
-        Board([[None; BOARDHEIGHT]; BOARDWIDTH])
+    fn new(size: &config::BoardSize) -> Board {
+        Board {
+            width: size.width,
+            height: size.height,
+            buffer: size.buffer,
+            cells: vec![vec![None; size.buffer + size.height]; size.width],
+        }
     }
 
     fn contains(&self, x: isize, y: isize) -> bool {
         // How to encode this constraint into a type?
-        x >= 0 && x < BOARDWIDTH as isize && y < BOARDHEIGHT as isize
+        x >= 0
+            && x < self.width as isize
+            && y >= -(self.buffer as isize)
+            && y < self.height as isize
+    }
+
+    /// The contents of a cell that `contains` accepts.
+    fn get(&self, x: isize, y: isize) -> Option<Block> {
+        self.cells[x as usize][(y + self.buffer as isize) as usize]
+    }
+
+    /// Puts the piece at the top middle of the board.
+    fn move_to_spawn(&self, piece: &mut Piece) {
+        piece.x = (self.width / 2) as isize - (TEMPLATEWIDTH / 2) as isize + piece.shape.spawn.0;
+        piece.y = -2 + piece.shape.spawn.1;
     }
 
     fn is_valid_position(&self, piece: &Piece, adj_x: isize, adj_y: isize) -> bool {
-        for x in 0..TEMPLATEWIDTH {
-            for y in 0..TEMPLATEHEIGHT {
-                let is_above_board = (y as isize + piece.y + adj_y) < 0;
-                if is_above_board || piece.shape.template()[piece.rotation][y][x] == BLANK {
-                    continue;
-                }
-                let new_x = x as isize + piece.x as isize + adj_x;
-                let new_y = y as isize + piece.y + adj_y;
-                if !self.contains(new_x, new_y) {
-                    return false;
-                }
-                if !self.0[new_x as usize][new_y as usize].is_none() {
-                    return false;
-                }
-            }
+        piece.cells().into_iter().all(|(x, y)| {
+            let (x, y) = (x + adj_x, y + adj_y);
+            self.contains(x, y) && self.get(x, y).is_none()
+        })
+    }
+
+    /// Shifts the piece by the given offset if the destination is free.
+    fn try_move(&self, piece: &mut Piece, adj_x: isize, adj_y: isize) -> bool {
+        if !self.is_valid_position(piece, adj_x, adj_y) {
+            return false;
         }
+        piece.x += adj_x;
+        piece.y += adj_y;
         true
     }
 
-    fn add(&mut self, piece: &Piece) {
-        for x in 0..TEMPLATEWIDTH {
-            for y in 0..TEMPLATEHEIGHT {
-                if piece.shape.template()[piece.rotation][y][x] != BLANK {
-                    // Not sure why the Python version doesn't need this guard clause.
-                    if self.contains(x as isize + piece.x as isize, y as isize + piece.y) {
-                        self.0[x + piece.x][y + piece.y as usize] = Some(piece.color);
-                    }
-                }
+    /// Rotates the piece one step, trying each of the shape's kicks in turn
+    /// when the unkicked rotation is blocked. Leaves the piece untouched on failure.
+    fn try_rotate(&self, piece: &mut Piece, rotation: Rotation) -> bool {
+        let previous = piece.rotation;
+        let count = piece.rotations();
+        piece.rotation = match rotation {
+            Rotation::Cw => (previous + 1) % count,
+            Rotation::Ccw => (previous + count - 1) % count,
+        };
+        let shape = piece.shape.clone();
+        for &(adj_x, adj_y) in shape.kicks.iter() {
+            if self.try_move(piece, adj_x, adj_y) {
+                return true;
             }
         }
+        piece.rotation = previous;
+        false
+    }
+
+    /// Moves the piece as far in the given direction as it will go.
+    fn slide(&self, piece: &mut Piece, adj_x: isize, adj_y: isize) {
+        while self.try_move(piece, adj_x, adj_y) {}
+    }
+
+    fn add(&mut self, piece: &Piece) {
+        let buffer = self.buffer as isize;
+        for (x, y, block) in piece.blocks() {
+            self.cells[x as usize][(y + buffer) as usize] = Some(block);
+        }
     }
 
     fn remove_complete_lines(&mut self) -> u32 {
         let mut count_removed = 0;
-        let mut y = BOARDHEIGHT - 1;
+        let mut y = self.buffer + self.height - 1;
         loop {
             if self.is_complete_line(y) {
+                // Blocks either side of the line lose their links into it.
+                for column in self.cells.iter_mut() {
+                    if let Some(block) = column.get_mut(y + 1).and_then(Option::as_mut) {
+                        block.links &= !LINK_UP;
+                    }
+                    if let Some(block) = y.checked_sub(1).and_then(|above| column[above].as_mut()) {
+                        block.links &= !LINK_DOWN;
+                    }
+                }
                 for pulldown_y in (1..y + 1).rev() {
-                    for x in 0..BOARDWIDTH {
-                        self.0[x][pulldown_y] = self.0[x][pulldown_y - 1]
+                    for x in 0..self.width {
+                        self.cells[x][pulldown_y] = self.cells[x][pulldown_y - 1]
                     }
                 }
-                for x in 0..BOARDWIDTH {
-                    self.0[x][0] = None
+                for x in 0..self.width {
+                    self.cells[x][0] = None
                 }
                 count_removed += 1;
             } else {
@@ -394,229 +834,776 @@ impl Board {
         count_removed
     }
 
+    /// Pushes the stack up a row and fills the bottom row except for `hole`.
+    /// Returns false if blocks were pushed off the top of the buffer.
+    fn add_garbage(&mut self, hole: usize, color: usize) -> bool {
+        let mut fits = true;
+        for (x, column) in self.cells.iter_mut().enumerate() {
+            fits &= column.remove(0).is_none();
+            let block = Block {
+                color,
+                shape: None,
+                links: 0,
+            };
+            column.push(if x == hole { None } else { Some(block) });
+        }
+        fits
+    }
+
+    /// Full rows, counted from the top of the visible field.
+    fn complete_lines(&self) -> Vec<isize> {
+        (0..self.buffer + self.height)
+            .filter(|&y| self.is_complete_line(y))
+            .map(|y| y as isize - self.buffer as isize)
+            .collect()
+    }
+
     fn is_complete_line(&self, y: usize) -> bool {
-        for x in 0..BOARDWIDTH {
-            if self.0[x][y].is_none() {
+        for x in 0..self.width {
+            if self.cells[x][y].is_none() {
                 return false;
             }
         }
         true
     }
 
-    fn draw(&self, c: &Context, g: &mut GlGraphics) {
+    /// The highest row with a block in it, counted from the top of the visible
+    /// field, or None when the board is empty.
+    fn stack_top(&self) -> Option<isize> {
+        (0..self.buffer + self.height)
+            .find(|&y| (0..self.width).any(|x| self.cells[x][y].is_some()))
+            .map(|y| y as isize - self.buffer as isize)
+    }
+
+    fn draw(
+        &self,
+        layout: &Layout,
+        painter: &BlockPainter,
+        border: Color,
+        c: &Context,
+        g: &mut GlGraphics,
+    ) {
         use graphics::Rectangle;
 
         let border_rect = [
-            (XMARGIN - 3) as f64,
-            (TOPMARGIN - 7) as f64,
-            ((BOARDWIDTH as u32 * BOXSIZE) + 8) as f64,
-            ((BOARDHEIGHT as u32 * BOXSIZE) + 8) as f64,
+            layout.x_margin - 3.0,
+            layout.top_margin - 7.0,
+            (self.width as f64 * layout.box_size) + 8.0,
+            (self.height as f64 * layout.box_size) + 8.0,
         ];
 
-        Rectangle::new_border(BORDERCOLOR, 5.0).draw(border_rect, &c.draw_state, c.transform, g);
+        Rectangle::new_border(border, 5.0).draw(border_rect, &c.draw_state, c.transform, g);
 
         let board_rect = [
-            XMARGIN as f64,
-            TOPMARGIN as f64,
-            (BOARDWIDTH as u32 * BOXSIZE) as f64,
-            (BOARDHEIGHT as u32 * BOXSIZE) as f64,
+            layout.x_margin,
+            layout.top_margin,
+            self.width as f64 * layout.box_size,
+            self.height as f64 * layout.box_size,
         ];
 
-        Rectangle::new(BGCOLOR).draw(board_rect, &c.draw_state, c.transform, g);
-        for x in 0..BOARDWIDTH {
-            for y in 0..BOARDHEIGHT {
-                let (pixel_x, pixel_y) = xy_to_pxy(x, y);
-                self.draw_box(pixel_x, pixel_y, self.0[x][y], c, g);
+        Rectangle::new(layout.theme.background).draw(board_rect, &c.draw_state, c.transform, g);
+        for x in 0..self.width {
+            for y in 0..self.height {
+                let (x, y) = (x as isize, y as isize);
+                let (pixel_x, pixel_y) = layout.cell(x, y);
+                if let Some(block) = self.get(x, y) {
+                    painter.draw(block, pixel_x, pixel_y, layout.box_size, c, g);
+                }
             }
         }
     }
 }
 
 pub struct Tetris {
+    mode: Mode,
+    settings: Settings,
+    shapes: ShapeSet,
+    painter: BlockPainter,
+    layout: Layout,
     board: Board,
-    rng: ThreadRng,
+    // Seeded so a game's pieces can be dealt again.
+    seed: u32,
+    rng: StdRng,
     last_fall_time: Instant,
     last_move_down_time: Instant,
     last_move_sideways_time: Instant,
     moving: Moving,
     score: u32,
+    lines: u32,
     falling_piece: Option<Piece>,
     next_piece: Piece,
+    held_piece: Option<Piece>,
+    // Only one hold is allowed per piece.
+    hold_used: bool,
     level: u32,
     fall_freq: Duration,
+    // Number of pieces spawned so far this game.
+    piece_count: u32,
+    // The falling piece as it spawned, used to work out the optimal inputs.
+    spawned_piece: Piece,
+    inputs: u32,
+    // Pieces checked for finesse.
+    pieces_placed: u32,
+    finesse_faults: u32,
+    // Pieces added to the stack, which garbage is timed by.
+    pieces_locked: u32,
+    restarts: u32,
+    top_out: Option<TopOut>,
+    // When the game was paused, so the timers can be moved on by however
+    // long the pause lasted.
+    paused_at: Option<Instant>,
+    started_at: Instant,
+    // Time spent paused, which doesn't count towards the game time.
+    paused_for: Duration,
+    stats: Stats,
+    /// What happened during the last call to `run`.
+    events: Vec<GameEvent>,
+    // Consecutive pieces that cleared lines.
+    combo: u32,
+    // Whether the falling piece's last successful move was a rotation.
+    spun: bool,
+    // Whether the last clear was a tetris or T-spin, for back-to-backs.
+    difficult_clear: bool,
+    // When full rows started clearing, while they are.
+    clear_started: Option<Instant>,
+    // When the next piece may appear.
+    spawn_at: Instant,
+    // Whether the falling piece falls by itself. With the
+    // `gravity_after_drop` assist it waits for the first drop.
+    gravity_on: bool,
+    // Whether the stack is within `danger_rows` of the top.
+    in_danger: bool,
+    effects: Effects,
 }
 
 impl Tetris {
-    fn new() -> Tetris {
+    fn new(
+        mode: Mode,
+        settings: &Settings,
+        shapes: &ShapeSet,
+        theme: &Rc<Theme>,
+        skin: &Rc<Skin>,
+    ) -> Tetris {
         let last_move_down_time = Instant::now();
         let last_move_sideways_time = Instant::now();
         let last_fall_time = Instant::now();
         let moving = Moving::Not;
         let score: u32 = 0;
         let (level, fall_freq) = calculate_level_and_fall_freq(score);
-        let mut rng = rand::thread_rng();
-        let falling_piece = Some(Piece::new(&mut rng));
-        let next_piece = Piece::new(&mut rng);
+        let seed = rand::random();
+        let mut rng = StdRng::seed_from_u64(seed as u64);
+        let random_rotation = settings.ruleset.random_spawn_rotation;
+        let mut size = settings.board.clone();
+        size.width = shapes.board_width.unwrap_or(size.width);
+        let board = Board::new(&size);
+        let layout = Layout::new(&settings.display, &board, theme);
+        let mut falling_piece = Piece::new(&shapes.shapes, &mut rng, random_rotation);
+        board.move_to_spawn(&mut falling_piece);
+        let spawned_piece = falling_piece.clone();
+        let next_piece = Piece::new(&shapes.shapes, &mut rng, random_rotation);
         Tetris {
-            board: Board::new(),
+            mode,
+            settings: settings.clone(),
+            shapes: shapes.clone(),
+            painter: BlockPainter::new(skin, shapes, settings.display.block_patterns),
+            layout,
+            board,
+            seed,
             rng,
             last_fall_time,
             last_move_down_time,
             last_move_sideways_time,
             moving,
             score,
-            falling_piece,
+            lines: 0,
+            falling_piece: Some(falling_piece),
             next_piece,
+            held_piece: None,
+            hold_used: false,
             level,
             fall_freq,
+            piece_count: 1,
+            spawned_piece,
+            inputs: 0,
+            pieces_placed: 0,
+            finesse_faults: 0,
+            pieces_locked: 0,
+            restarts: 0,
+            top_out: None,
+            paused_at: None,
+            started_at: Instant::now(),
+            paused_for: Duration::from_secs(0),
+            stats: Stats::default(),
+            events: Vec::new(),
+            combo: 0,
+            spun: false,
+            difficult_clear: false,
+            clear_started: None,
+            spawn_at: Instant::now(),
+            gravity_on: !settings.assist.gravity_after_drop,
+            in_danger: false,
+            effects: Effects::new(&settings.effects),
+        }
+    }
+
+    /// Makes `piece` the falling piece. Returns false if it spawns overlapping
+    /// the stack, which is a block out.
+    fn spawn(&mut self, mut piece: Piece) -> bool {
+        self.board.move_to_spawn(&mut piece);
+        self.spawned_piece = piece.clone();
+        self.inputs = 0;
+        self.spun = false;
+        self.last_fall_time = Instant::now();
+        self.gravity_on = !self.settings.assist.gravity_after_drop;
+        let valid = self.board.is_valid_position(&piece, 0, 0);
+        self.falling_piece = Some(piece);
+        if !valid {
+            self.top_out = Some(TopOut::Block);
+        }
+        valid
+    }
+
+    /// Swaps the falling piece with the held one, or with the next piece if
+    /// nothing is held yet.
+    fn hold(&mut self) -> bool {
+        use std::mem::replace;
+        self.emit(GameEvent::Held);
+        let piece = self.falling_piece.take().unwrap();
+        let next = match self.held_piece.replace(piece) {
+            Some(held) => held,
+            None => {
+                self.piece_count += 1;
+                let piece = Piece::new(
+                    &self.shapes.shapes,
+                    &mut self.rng,
+                    self.settings.ruleset.random_spawn_rotation,
+                );
+                replace(&mut self.next_piece, piece)
+            }
+        };
+        self.hold_used = true;
+        self.moving = Moving::Not;
+        self.spawn(next)
+    }
+
+    fn pause(&mut self) {
+        self.paused_at = Some(Instant::now());
+    }
+
+    /// Picks up where the game was paused, as though no time had passed.
+    fn resume(&mut self) {
+        if let Some(paused_at) = self.paused_at.take() {
+            let paused = paused_at.elapsed();
+            self.last_fall_time += paused;
+            self.last_move_down_time += paused;
+            self.last_move_sideways_time += paused;
+            self.paused_for += paused;
+            self.spawn_at += paused;
+            if let Some(started) = &mut self.clear_started {
+                *started += paused;
+            }
+        }
+        // Keys may have been let go while the menu was up.
+        self.moving = Moving::Not;
+    }
+
+    /// How long the game has been played, leaving out pauses.
+    fn play_time(&self) -> Duration {
+        let paused = self
+            .paused_at
+            .map_or(Duration::from_secs(0), |at| at.elapsed());
+        self.started_at.elapsed() - self.paused_for - paused
+    }
+
+    fn emit(&mut self, event: GameEvent) {
+        self.stats.record(&event);
+        self.events.push(event);
+    }
+
+    fn game_stats(&self) -> Stats {
+        Stats {
+            games: 1,
+            time_ms: self.play_time().as_millis() as u64,
+            ..self.stats.clone()
+        }
+    }
+
+    /// Whether a T locking in place got there by rotating into a spot with
+    /// at least three of the four corners around its center filled.
+    fn is_t_spin(&self, piece: &Piece) -> bool {
+        let cells = piece.cells();
+        if !self.spun || piece.shape.name() != "T" || cells.len() != 4 {
+            return false;
+        }
+        let center = cells.iter().find(|&&(x, y)| {
+            [(x - 1, y), (x + 1, y), (x, y - 1), (x, y + 1)]
+                .iter()
+                .filter(|neighbour| cells.contains(neighbour))
+                .count()
+                == 3
+        });
+        let (x, y) = match center {
+            Some(&center) => center,
+            None => return false,
+        };
+        let filled = [
+            (x - 1, y - 1),
+            (x + 1, y - 1),
+            (x - 1, y + 1),
+            (x + 1, y + 1),
+        ]
+        .iter()
+        .filter(|&&(x, y)| !self.board.contains(x, y) || self.board.get(x, y).is_some())
+        .count();
+        filled >= 3
+    }
+
+    fn table_key(&self) -> TableKey {
+        TableKey::new(self.mode, &self.settings, &self.shapes)
+    }
+
+    fn score_entry(&self, name: String) -> scores::Entry {
+        scores::Entry {
+            name,
+            score: self.score,
+            lines: self.lines,
+            level: self.level,
+            time_ms: self.play_time().as_millis() as u64,
+            date: scores::now(),
+            seed: self.seed,
+        }
+    }
+
+    /// Runs out the line clear and entry delay after a piece locks, then
+    /// spawns the next piece. Returns the state to stay in until then.
+    fn wait(&mut self) -> Option<State> {
+        use std::mem::replace;
+        if let Some(started) = self.clear_started {
+            if started.elapsed() < self.settings.ruleset.line_clear() {
+                return Some(State::Run);
+            }
+            self.clear_started = None;
+            if !self.finish_lock() {
+                return Some(State::GameOver);
+            }
+        }
+        if self.falling_piece.is_none() {
+            if Instant::now() < self.spawn_at {
+                return Some(State::Run);
+            }
+            let piece = Piece::new(
+                &self.shapes.shapes,
+                &mut self.rng,
+                self.settings.ruleset.random_spawn_rotation,
+            );
+            let piece = replace(&mut self.next_piece, piece);
+            self.piece_count += 1;
+            if !self.spawn(piece) {
+                return Some(State::GameOver);
+            }
+        }
+        None
+    }
+
+    /// Collapses full rows, then adds any garbage and updates the level for
+    /// the piece that just locked. Returns false on a garbage out.
+    fn finish_lock(&mut self) -> bool {
+        self.board.remove_complete_lines();
+        let interval = self.settings.ruleset.garbage_interval;
+        if interval > 0 && self.pieces_locked.is_multiple_of(interval) {
+            let hole = self.rng.gen_range(0, self.board.width);
+            let color = self.shapes.garbage();
+            if !self.board.add_garbage(hole, color) && self.settings.ruleset.garbage_out {
+                self.top_out = Some(TopOut::Garbage);
+                return false;
+            }
+        }
+        let (level, fall_freq) = calculate_level_and_fall_freq(self.score);
+        if level > self.level {
+            self.emit(GameEvent::LevelUp { level });
+        }
+        self.level = level;
+        self.fall_freq = fall_freq;
+        self.spawn_at = Instant::now() + self.settings.ruleset.entry_delay();
+        let danger_rows = self.settings.display.danger_rows as isize;
+        let in_danger =
+            danger_rows > 0 && self.board.stack_top().is_some_and(|top| top < danger_rows);
+        if in_danger && !self.in_danger {
+            self.emit(GameEvent::Danger);
+        }
+        self.in_danger = in_danger;
+        true
+    }
+
+    // Pulses from the theme's border color to its danger color and back
+    // while the stack is near the top.
+    fn border_color(&self) -> Color {
+        let theme = &self.layout.theme;
+        if !self.in_danger {
+            return theme.border;
+        }
+        let phase = self.started_at.elapsed().as_secs_f64() * std::f64::consts::TAU;
+        let amount = (0.5 - 0.5 * (phase * 1.5).cos()) as f32;
+        let mut color = theme.border;
+        for (part, danger) in color.iter_mut().zip(theme.danger.iter()) {
+            *part += (danger - *part) * amount;
+        }
+        color
+    }
+
+    // Marks where the next piece would spawn when something is in the way.
+    fn draw_blocked_spawn(&self, c: &Context, g: &mut GlGraphics) {
+        use graphics::Rectangle;
+        let layout = &self.layout;
+        let mut next = self.next_piece.clone();
+        self.board.move_to_spawn(&mut next);
+        if self.board.is_valid_position(&next, 0, 0) {
+            return;
+        }
+        let mut color = layout.theme.danger;
+        color[3] = 0.5;
+        for (x, y) in next.cells() {
+            let (px, py) = layout.cell(x, y);
+            let rect = [px, py, layout.box_size, layout.box_size];
+            Rectangle::new(color).draw(rect, &c.draw_state, c.transform, g);
+        }
+    }
+
+    /// Flashes the full rows, then wipes them out from the middle.
+    fn draw_clearing(&self, progress: f64, c: &Context, g: &mut GlGraphics) {
+        use graphics::Rectangle;
+        let layout = &self.layout;
+        let mut flash = layout.theme.text;
+        flash[3] = 0.6 * (1.0 - progress) as f32;
+        let middle = self.board.width as f64 / 2.0;
+        for y in self.board.complete_lines() {
+            if y < 0 {
+                continue;
+            }
+            for x in 0..self.board.width {
+                let (px, py) = layout.cell(x as isize, y);
+                let rect = [px, py, layout.box_size, layout.box_size];
+                let distance = (x as f64 + 0.5 - middle).abs();
+                let color = if distance < progress * middle {
+                    layout.theme.background
+                } else {
+                    flash
+                };
+                Rectangle::new(color).draw(rect, &c.draw_state, c.transform, g);
+            }
+        }
+    }
+
+    fn render<E: GenericEvent>(&mut self, e: &E, gl: &mut GlGraphics, glyphs: &mut GlyphCache) {
+        self.effects
+            .update(&self.events, &self.layout, self.board.width);
+        if let Some(args) = e.render_args() {
+            let viewport = args.viewport();
+            gl.draw(viewport, |c, g| {
+                use graphics::clear;
+                let layout = &self.layout;
+                clear(layout.theme.background, g);
+                let c = layout.fit(c, args.window_size);
+                let painter = &self.painter;
+                let board_c = self.effects.shaken(&c);
+                self.board
+                    .draw(layout, painter, self.border_color(), &board_c, g);
+                if let Some(started) = self.clear_started {
+                    let length = self.settings.ruleset.line_clear().as_secs_f64();
+                    let progress = (started.elapsed().as_secs_f64() / length).min(1.0);
+                    self.draw_clearing(progress, &board_c, g);
+                }
+                self.draw_status(&c, g, glyphs);
+                let mut font = Text::new(18);
+                font.color = layout.theme.text;
+                let next_x = layout.width - 6.0 * layout.box_size;
+                font.draw(
+                    "Next:",
+                    glyphs,
+                    &c.draw_state,
+                    c.transform.trans(next_x, 80.0f64),
+                    g,
+                )
+                .expect("Unable to draw string");
+                self.next_piece
+                    .draw_at(next_x, 100.0, layout, painter, &c, g);
+                font.draw(
+                    "Hold:",
+                    glyphs,
+                    &c.draw_state,
+                    c.transform.trans(20.0f64, 80.0f64),
+                    g,
+                )
+                .expect("Unable to draw string");
+                if let Some(held_piece) = &self.held_piece {
+                    held_piece.draw_at(20.0, 100.0, layout, painter, &c, g);
+                }
+                if let Some(falling_piece) = &self.falling_piece {
+                    falling_piece.draw(layout, painter, &board_c, g);
+                }
+                // The cleared rows haven't collapsed yet, so the spawn area
+                // isn't settled.
+                if self.clear_started.is_none() {
+                    self.draw_blocked_spawn(&board_c, g);
+                }
+                self.effects.draw(layout, &board_c, g, glyphs);
+            });
         }
     }
 
     fn run<E: GenericEvent>(
         &mut self,
         e: E,
+        actions: &ActionEvents,
         gl: &mut GlGraphics,
         glyphs: &mut GlyphCache,
     ) -> State {
         use std::mem::replace;
-        if self.falling_piece.is_none() {
-            self.falling_piece = Some(replace(&mut self.next_piece, Piece::new(&mut self.rng)));
-            self.last_fall_time = Instant::now();
-
-            if !self
-                .board
-                .is_valid_position(self.falling_piece.as_ref().unwrap(), 0, 0)
-            {
-                return State::GameOver;
+        self.events.clear();
+        if e.focus_args() == Some(false) {
+            self.pause();
+            return State::Paused;
+        }
+        // These work even between pieces.
+        for action in actions.released.iter() {
+            match action {
+                Action::MoveLeft | Action::MoveRight | Action::SoftDrop => {
+                    self.moving = Moving::Not
+                }
+                _ => {}
             }
         }
-        let fp = self.falling_piece.as_mut().unwrap();
-        if let Some(Button::Keyboard(key)) = e.release_args() {
-            match key {
-                Key::P => return State::Paused,
-                Key::Left | Key::Right | Key::Down => self.moving = Moving::Not,
+        for action in actions.pressed.iter() {
+            match action {
+                Action::Pause => {
+                    self.pause();
+                    return State::Paused;
+                }
+                Action::Quit => {
+                    self.pause();
+                    return State::ConfirmQuit;
+                }
                 _ => {}
             }
         }
-        if let Some(Button::Keyboard(key)) = e.press_args() {
-            match key {
-                Key::P => return State::Paused,
-                Key::Left | Key::A => {
-                    if self.board.is_valid_position(fp, -1, 0) {
-                        fp.x -= 1;
-                        self.moving = Moving::Left;
-                        self.last_move_sideways_time = Instant::now();
-                    }
+        if let Some(state) = self.wait() {
+            self.render(&e, gl, glyphs);
+            return state;
+        }
+        let fp = self.falling_piece.as_mut().unwrap();
+        for action in actions.pressed.iter() {
+            // Drops don't count towards finesse, as in `pathfind::cost`.
+            if let Action::MoveLeft | Action::MoveRight | Action::RotateCw | Action::RotateCcw =
+                action
+            {
+                self.inputs += 1;
+            }
+            if let Action::MoveLeft
+            | Action::MoveRight
+            | Action::RotateCw
+            | Action::RotateCcw
+            | Action::SoftDrop
+            | Action::HardDrop
+            | Action::Hold = action
+            {
+                self.stats.inputs += 1;
+            }
+            match action {
+                // The falling piece is borrowed, so these events skip `emit`.
+                // Stats don't count them anyway.
+                Action::MoveLeft if self.board.try_move(fp, -1, 0) => {
+                    self.moving = Moving::Left;
+                    self.last_move_sideways_time = Instant::now();
+                    self.spun = false;
+                    self.events.push(GameEvent::Moved);
                 }
-                Key::Right | Key::D => {
-                    if self.board.is_valid_position(fp, 1, 0) {
-                        fp.x += 1;
-                        self.moving = Moving::Right;
-                        self.last_move_sideways_time = Instant::now();
-                    }
+                Action::MoveRight if self.board.try_move(fp, 1, 0) => {
+                    self.moving = Moving::Right;
+                    self.last_move_sideways_time = Instant::now();
+                    self.spun = false;
+                    self.events.push(GameEvent::Moved);
                 }
-                Key::Up | Key::W => {
-                    fp.rotation = (fp.rotation + 1) % fp.shape.template().len();
-                    if !self.board.is_valid_position(fp, 0, 0) {
-                        fp.rotation = (fp.rotation - 1) % fp.shape.template().len();
-                    }
+                Action::RotateCw if self.board.try_rotate(fp, Rotation::Cw) => {
+                    self.spun = true;
+                    self.events.push(GameEvent::Rotated);
                 }
-                Key::Q => {
-                    fp.rotation = (fp.rotation - 1) % fp.shape.template().len();
-                    if !self.board.is_valid_position(fp, 0, 0) {
-                        fp.rotation = (fp.rotation + 1) % fp.shape.template().len();
-                    }
+                Action::RotateCcw if self.board.try_rotate(fp, Rotation::Ccw) => {
+                    self.spun = true;
+                    self.events.push(GameEvent::Rotated);
                 }
-                Key::Down => {
+                Action::SoftDrop => {
                     self.moving = Moving::Down;
-                    if self.board.is_valid_position(fp, 0, 1) {
-                        fp.y += 1;
+                    self.gravity_on = true;
+                    if self.board.try_move(fp, 0, 1) {
+                        self.spun = false;
                     }
                     self.last_move_down_time = Instant::now();
                 }
-                Key::Space => {
+                Action::HardDrop => {
                     self.moving = Moving::Not;
-                    for i in 1..BOARDHEIGHT {
-                        if !self.board.is_valid_position(fp, 0, i as isize) {
-                            break;
-                        }
-                        fp.y += i as isize - 1;
+                    self.gravity_on = true;
+                    if self.board.try_move(fp, 0, 1) {
+                        self.board.slide(fp, 0, 1);
+                        self.spun = false;
                     }
+                    self.events.push(GameEvent::HardDropped);
+                }
+                Action::Hold if self.settings.ruleset.hold && !self.hold_used => {
+                    if !self.hold() {
+                        return State::GameOver;
+                    }
+                    return State::Run;
                 }
                 _ => {}
             }
         }
         if (self.moving == Moving::Left || self.moving == Moving::Right)
-            && ((Instant::now() - self.last_move_sideways_time) > MOVESIDEWAYSFREQ)
+            && ((Instant::now() - self.last_move_sideways_time)
+                > self.settings.handling.auto_repeat())
         {
-            if self.moving == Moving::Left && self.board.is_valid_position(fp, -1, 0) {
-                fp.x -= 1;
-            }
-            if self.moving == Moving::Right && self.board.is_valid_position(fp, 1, 0) {
-                fp.x += 1;
+            let adj_x = if self.moving == Moving::Left { -1 } else { 1 };
+            if self.board.try_move(fp, adj_x, 0) {
+                self.spun = false;
+                self.events.push(GameEvent::Moved);
             }
             self.last_move_sideways_time = Instant::now();
         }
 
         if self.moving == Moving::Down
-            && ((Instant::now() - self.last_move_sideways_time) > MOVEDOWNFREQ)
-            && self.board.is_valid_position(fp, 0, 1)
+            && ((Instant::now() - self.last_move_down_time) > self.settings.handling.soft_drop())
+            && self.board.try_move(fp, 0, 1)
         {
-            fp.y += 1;
             self.last_move_down_time = Instant::now();
+            self.spun = false;
         }
-        drop(fp);
-
-        if (Instant::now() - self.last_fall_time) > self.fall_freq {
-            let fp = self.falling_piece.as_mut().unwrap();
-            if !self.board.is_valid_position(fp, 0, 1) {
-                self.board.add(fp);
-                self.score += self.board.remove_complete_lines();
-                let (level, fall_freq) = calculate_level_and_fall_freq(self.score);
-                self.level = level;
-                self.fall_freq = fall_freq;
-                self.falling_piece = None;
-                self.moving = Moving::Not;
+
+        // A landed piece locks after the lock delay rather than the next fall.
+        let assist = &self.settings.assist;
+        let wait = if self.board.is_valid_position(fp, 0, 1) {
+            assist.fall_interval(self.fall_freq)
+        } else {
+            assist.lock_delay(self.fall_freq)
+        };
+        if self.gravity_on && (Instant::now() - self.last_fall_time) > wait {
+            if !self.board.try_move(fp, 0, 1) {
+                if self.check_finesse() && self.mode == Mode::FinesseTraining {
+                    // The restarts are all one session as far as the stats go.
+                    let previous = replace(
+                        self,
+                        Tetris::new(
+                            self.mode,
+                            &self.settings,
+                            &self.shapes,
+                            &self.layout.theme,
+                            &self.painter.skin,
+                        ),
+                    );
+                    self.restarts = previous.restarts + 1;
+                    self.stats = previous.stats;
+                    self.started_at = previous.started_at;
+                    self.paused_for = previous.paused_for;
+                    return State::Run;
+                }
+                if !self.lock() {
+                    return State::GameOver;
+                }
             } else {
-                fp.y += 1;
                 self.last_fall_time = Instant::now();
             }
         }
 
-        if let Some(args) = e.render_args() {
-            let viewport = args.viewport();
-            gl.draw(viewport, |c, g| {
-                use graphics::clear;
-                clear(BLACK, g);
-                self.board.draw(&c, g);
-                self.draw_status(&c, g, glyphs);
-                let mut font = Text::new(18);
-                font.color = TEXTCOLOR;
-                font.draw(
-                    "Next:",
-                    glyphs,
-                    &c.draw_state,
-                    c.transform.trans((WIDTH - 120) as f64, 80.0f64),
-                    g,
-                )
-                .expect("Unable to draw string");
-                self.next_piece.draw_at(WIDTH - 120, 100, &c, g);
-                if !self.falling_piece.is_none() {
-                    self.falling_piece.as_ref().unwrap().draw(&c, g);
-                }
+        self.render(&e, gl, glyphs);
+        State::Run
+    }
+
+    /// Adds the falling piece to the stack and scores any full rows, then
+    /// either starts clearing them or finishes the lock straight away.
+    /// Returns false on a lock out or garbage out.
+    fn lock(&mut self) -> bool {
+        let fp = self.falling_piece.as_ref().unwrap();
+        let t_spin = self.is_t_spin(fp);
+        self.board.add(fp);
+        self.pieces_locked += 1;
+        let lock_out = self.is_lock_out(fp);
+        self.emit(GameEvent::Locked { t_spin });
+        if lock_out {
+            self.top_out = Some(TopOut::Lock);
+            return false;
+        }
+        let rows = self.board.complete_lines();
+        let lines = rows.len() as u32;
+        self.lines += lines;
+        self.score += lines;
+        if lines > 0 {
+            self.combo += 1;
+            let difficult = t_spin || lines >= 4;
+            self.emit(GameEvent::LinesCleared {
+                lines,
+                combo: self.combo - 1,
+                t_spin,
+                back_to_back: difficult && self.difficult_clear,
+                rows,
             });
+            self.difficult_clear = difficult;
+        } else {
+            self.combo = 0;
+        }
+        self.falling_piece = None;
+        self.hold_used = false;
+        self.moving = Moving::Not;
+        if lines > 0 && self.settings.ruleset.line_clear_frames > 0 {
+            self.clear_started = Some(Instant::now());
+            true
+        } else {
+            self.finish_lock()
+        }
+    }
+
+    /// Whether locking `piece` ends the game. Cells in the hidden buffer
+    /// have negative y.
+    fn is_lock_out(&self, piece: &Piece) -> bool {
+        let ruleset = &self.settings.ruleset;
+        let cells = piece.cells();
+        let above = cells.iter().filter(|&&(_, y)| y < 0).count();
+        ruleset.lock_out
+            && if ruleset.partial_lock_out {
+                above > 0
+            } else {
+                above == cells.len()
+            }
+    }
+
+    /// Compares the inputs used on the falling piece against the fewest that
+    /// reach the same placement, not counting the final drop.
+    fn check_finesse(&mut self) -> bool {
+        let fp = self.falling_piece.as_ref().unwrap();
+        let optimal = pathfind::find_path(&self.board, &self.spawned_piece, Placement::of(fp))
+            .map(|path| pathfind::cost(&path));
+        self.pieces_placed += 1;
+        match optimal {
+            Some(optimal) if self.inputs > optimal => {
+                self.finesse_faults += 1;
+                true
+            }
+            _ => false,
         }
-        State::Run
+    }
+
+    fn finesse_summary(&self) -> String {
+        format!(
+            "Finesse faults: {} in {} pieces",
+            self.finesse_faults, self.pieces_placed
+        )
     }
 
     fn draw_status(&self, c: &Context, g: &mut GlGraphics, glyphs: &mut GlyphCache) {
         let mut font = Text::new(18);
-        font.color = TEXTCOLOR;
+        font.color = self.layout.theme.text;
         font.draw_center(
             format!("Score: {}", self.score).as_str(),
             glyphs,
             &c.draw_state,
-            c.transform.trans((WIDTH - 150) as f64, 20.0f64),
+            c.transform.trans(self.layout.width - 150.0, 20.0f64),
             g,
         )
         .expect("Unable to draw string");
@@ -624,19 +1611,85 @@ impl Tetris {
             format!("Level: {}", self.level).as_str(),
             glyphs,
             &c.draw_state,
-            c.transform.trans((WIDTH - 150) as f64, 50.0f64),
+            c.transform.trans(self.layout.width - 150.0, 50.0f64),
+            g,
+        )
+        .expect("Unable to draw string");
+        let finesse = match self.mode {
+            Mode::Marathon | Mode::Pentomino => format!("Faults: {}", self.finesse_faults),
+            Mode::FinesseTraining => format!("Restarts: {}", self.restarts),
+        };
+        font.draw(
+            finesse.as_str(),
+            glyphs,
+            &c.draw_state,
+            c.transform.trans(20.0f64, 50.0f64),
             g,
         )
         .expect("Unable to draw string");
     }
 }
 
+// Level 13 is as fast as pieces fall; going any faster would go below zero.
+const MIN_FALL_FREQ: f64 = 0.01;
+
 fn calculate_level_and_fall_freq(score: u32) -> (u32, Duration) {
     let level = score / 10 + 1;
-    let fall_freq = Duration::from_secs_f64(0.27 - (level as f64 * 0.02));
+    let fall_freq = Duration::from_secs_f64((0.27 - (level as f64 * 0.02)).max(MIN_FALL_FREQ));
     (level, fall_freq)
 }
 
+/// The guideline conditions that end a game.
+#[derive(Clone, Copy, PartialEq)]
+enum TopOut {
+    Block,
+    Lock,
+    Garbage,
+}
+
+impl TopOut {
+    fn description(self) -> &'static str {
+        match self {
+            TopOut::Block => "Block out: no room for the next piece",
+            TopOut::Lock => "Lock out: piece locked above the field",
+            TopOut::Garbage => "Garbage out: stack pushed past the ceiling",
+        }
+    }
+}
+
+/// Things that happen during a game, for anything that reacts to them.
+#[derive(Clone, PartialEq)]
+enum GameEvent {
+    Moved,
+    Rotated,
+    HardDropped,
+    Held,
+    Locked {
+        t_spin: bool,
+    },
+    /// `combo` counts the clearing pieces in a row before this one, and
+    /// `back_to_back` is set when this and the last clear were both tetrises
+    /// or T-spins. `rows` count from the top of the visible field.
+    LinesCleared {
+        lines: u32,
+        combo: u32,
+        t_spin: bool,
+        back_to_back: bool,
+        rows: Vec<isize>,
+    },
+    LevelUp {
+        level: u32,
+    },
+    /// The stack has come within `danger_rows` of the top.
+    Danger,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Rotation {
+    Cw,
+    Ccw,
+}
+
 #[derive(PartialEq)]
 enum Moving {
     Down,
@@ -645,63 +1698,6 @@ enum Moving {
     Not,
 }
 
-fn show_text_screen<E: GenericEvent>(
-    text: &str,
-    e: E,
-    gl: &mut GlGraphics,
-    glyphs: &mut GlyphCache,
-    next_state: State,
-) -> Option<State> {
-    if let Some(Button::Keyboard(_)) = e.press_args() {
-        return Some(next_state);
-    }
-    if let Some(args) = e.render_args() {
-        let mut font = Text::new(1);
-        gl.draw(args.viewport(), |c, g| {
-            use graphics::clear;
-            clear(BLACK, g);
-            // let window_size = args.window_size;
-            font.font_size = 100;
-            font.color = TEXTSHADOWCOLOR;
-            font.draw_center(
-                text,
-                glyphs,
-                &c.draw_state,
-                c.transform.trans((WIDTH / 2) as f64, (HEIGHT / 2) as f64),
-                g,
-            )
-            .expect("Unable to draw string");
-            font.color = TEXTCOLOR;
-            font.draw_center(
-                text,
-                glyphs,
-                &c.draw_state,
-                c.transform
-                    .trans((WIDTH / 2 - 3) as f64, (HEIGHT / 2 - 3) as f64),
-                g,
-            )
-            .expect("Unable to draw string");
-            font.font_size = 18;
-            font.color = TEXTCOLOR;
-            font.draw_center(
-                "Press a key to play",
-                glyphs,
-                &c.draw_state,
-                c.transform
-                    .trans((WIDTH / 2) as f64, (HEIGHT / 2 + 100) as f64),
-                g,
-            )
-            .expect("Unable to draw string");
-            // gameboard_view.settings.position[0] =
-            //     (window_size[0] - gameboard_view.settings.size) / 2.0;
-            // gameboard_view.settings.position[1] =
-            //     (window_size[1] - gameboard_view.settings.size) / 2.0;
-            // gameboard_view.draw(&gameboard_controller, glyphs, &c, g);
-        });
-    }
-    None
-}
-
 // Hack to workaround API limitations...
 trait CenterText {
     fn draw_center<C, G>(
@@ -742,3 +1738,235 @@ impl CenterText for Text {
         self.draw(text, cache, draw_state, transform, g)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn piece(name: &str, rotation: usize, x: isize, y: isize) -> Piece {
+        let set = shapes::load(None).unwrap();
+        let shape = set
+            .shapes
+            .iter()
+            .find(|shape| shape.name() == name)
+            .unwrap();
+        Piece {
+            shape: shape.clone(),
+            rotation,
+            x,
+            y,
+            color: 0,
+        }
+    }
+
+    fn garbage() -> Block {
+        Block {
+            color: 0,
+            shape: None,
+            links: 0,
+        }
+    }
+
+    fn set(board: &mut Board, x: isize, y: isize) {
+        let buffer = board.buffer as isize;
+        board.cells[x as usize][(y + buffer) as usize] = Some(garbage());
+    }
+
+    // Fills row `y` apart from the columns in `gaps`.
+    fn fill_row(board: &mut Board, y: isize, gaps: &[isize]) {
+        for x in 0..board.width as isize {
+            if !gaps.contains(&x) {
+                set(board, x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn locks_pieces_into_the_buffer() {
+        let mut board = Board::new(&config::BoardSize::default());
+        assert!(board.contains(0, -20));
+        assert!(!board.contains(0, -21));
+        // A vertical I two rows into the buffer.
+        let i = piece("I", 0, 2, -2);
+        assert_eq!(i.cells(), vec![(4, -2), (4, -1), (4, 0), (4, 1)]);
+        assert!(board.is_valid_position(&i, 0, 0));
+        board.add(&i);
+        assert!(board.get(4, -2).is_some());
+        assert!(board.get(4, -3).is_none());
+        assert!(board.get(4, 1).is_some());
+        assert_eq!(board.stack_top(), Some(-2));
+        assert!(!board.is_valid_position(&i, 0, 0));
+    }
+
+    #[test]
+    fn clears_pull_buffer_rows_into_the_field() {
+        let mut board = Board::new(&config::BoardSize::default());
+        set(&mut board, 0, -1);
+        set(&mut board, 3, -3);
+        // A vertical I down the last column, completing the bottom two rows.
+        fill_row(&mut board, 19, &[9]);
+        fill_row(&mut board, 18, &[9]);
+        board.add(&piece("I", 0, 7, 16));
+        assert_eq!(board.complete_lines(), vec![18, 19]);
+        assert_eq!(board.remove_complete_lines(), 2);
+        assert!(board.get(0, 1).is_some());
+        assert!(board.get(0, -1).is_none());
+        assert!(board.get(3, -1).is_some());
+        assert!(board.get(3, -3).is_none());
+        // The rest of the I no longer links down into the cleared rows.
+        let top = board.get(9, 18).unwrap();
+        let bottom = board.get(9, 19).unwrap();
+        assert_eq!(top.links & LINK_DOWN, LINK_DOWN);
+        assert_eq!(bottom.links & LINK_DOWN, 0);
+        assert_eq!(bottom.links & LINK_UP, LINK_UP);
+        assert!(board.get(9, 17).is_none());
+        assert_eq!(board.stack_top(), Some(-1));
+    }
+
+    fn game(ruleset: config::Ruleset) -> Tetris {
+        let settings = Settings {
+            ruleset,
+            ..Settings::default()
+        };
+        let shapes = shapes::load(None).unwrap();
+        let theme = Rc::new(Theme::default());
+        Tetris::new(
+            Mode::Marathon,
+            &settings,
+            &shapes,
+            &theme,
+            &Rc::new(Skin::Flat),
+        )
+    }
+
+    // Locks an O with its top row at `y`.
+    fn lock_o(tetris: &mut Tetris, x: isize, y: isize) -> bool {
+        tetris.falling_piece = Some(piece("O", 0, x - 1, y - 2));
+        tetris.lock()
+    }
+
+    #[test]
+    fn blocks_out_when_the_spawn_is_covered() {
+        let mut tetris = game(config::Ruleset::default());
+        assert!(tetris.spawn(piece("O", 0, 0, 0)));
+        assert!(tetris.top_out.is_none());
+        for y in -3..3 {
+            fill_row(&mut tetris.board, y, &[]);
+        }
+        assert!(!tetris.spawn(piece("O", 0, 0, 0)));
+        assert!(tetris.top_out == Some(TopOut::Block));
+    }
+
+    #[test]
+    fn locks_out_above_the_field() {
+        let mut tetris = game(config::Ruleset::default());
+        // Half in the field is fine without partial lock out.
+        assert!(lock_o(&mut tetris, 0, -1));
+        assert!(tetris.top_out.is_none());
+        assert!(!lock_o(&mut tetris, 4, -4));
+        assert!(tetris.top_out == Some(TopOut::Lock));
+    }
+
+    #[test]
+    fn partially_locks_out() {
+        let ruleset = config::Ruleset {
+            partial_lock_out: true,
+            ..config::Ruleset::default()
+        };
+        let mut tetris = game(ruleset);
+        assert!(lock_o(&mut tetris, 0, 0));
+        assert!(!lock_o(&mut tetris, 4, -1));
+        assert!(tetris.top_out == Some(TopOut::Lock));
+    }
+
+    #[test]
+    fn lock_out_can_be_turned_off() {
+        let ruleset = config::Ruleset {
+            lock_out: false,
+            ..config::Ruleset::default()
+        };
+        let mut tetris = game(ruleset);
+        assert!(lock_o(&mut tetris, 4, -4));
+        assert!(tetris.top_out.is_none());
+    }
+
+    #[test]
+    fn adds_garbage_every_interval() {
+        let ruleset = config::Ruleset {
+            garbage_interval: 2,
+            ..config::Ruleset::default()
+        };
+        let mut tetris = game(ruleset);
+        assert!(lock_o(&mut tetris, 0, 18));
+        assert_eq!(tetris.board.stack_top(), Some(18));
+        assert!(lock_o(&mut tetris, 4, 18));
+        // The stack moved up a row over a garbage row with one hole.
+        assert_eq!(tetris.board.stack_top(), Some(17));
+        let filled = (0..10)
+            .filter(|&x| tetris.board.get(x, 19).is_some())
+            .count();
+        assert_eq!(filled, 9);
+        assert!(tetris.board.get(0, 17).is_some());
+        // Garbage isn't tied to the finesse count.
+        assert_eq!(tetris.pieces_placed, 0);
+    }
+
+    #[test]
+    fn garbage_outs_past_the_buffer() {
+        let ruleset = config::Ruleset {
+            garbage_interval: 1,
+            ..config::Ruleset::default()
+        };
+        let mut tetris = game(ruleset);
+        set(&mut tetris.board, 9, -20);
+        assert!(!lock_o(&mut tetris, 0, 18));
+        assert!(tetris.top_out == Some(TopOut::Garbage));
+    }
+
+    #[test]
+    fn garbage_out_can_be_turned_off() {
+        let ruleset = config::Ruleset {
+            garbage_interval: 1,
+            garbage_out: false,
+            ..config::Ruleset::default()
+        };
+        let mut tetris = game(ruleset);
+        set(&mut tetris.board, 9, -20);
+        set(&mut tetris.board, 9, -19);
+        assert!(lock_o(&mut tetris, 0, 18));
+        assert!(tetris.top_out.is_none());
+        // The top block was pushed off and lost; the one below moved up.
+        assert!(tetris.board.get(9, -20).is_some());
+        assert!(tetris.board.get(9, -19).is_none());
+    }
+
+    #[test]
+    fn garbage_leaves_one_hole() {
+        let mut board = Board::new(&config::BoardSize::default());
+        set(&mut board, 2, 19);
+        assert!(board.add_garbage(5, 3));
+        assert!(board.get(2, 18).is_some());
+        for x in 0..10 {
+            assert_eq!(board.get(x, 19).is_none(), x == 5);
+        }
+        assert_eq!(board.get(0, 19).unwrap().color, 3);
+    }
+
+    #[test]
+    fn table_keys_match_the_board_played_on() {
+        let settings = Settings::default();
+        let theme = Rc::new(Theme::default());
+        for shapes in [shapes::load(None).unwrap(), shapes::pentominoes()].iter() {
+            let tetris = Tetris::new(
+                Mode::Marathon,
+                &settings,
+                shapes,
+                &theme,
+                &Rc::new(Skin::Flat),
+            );
+            let key = TableKey::new(Mode::Marathon, &settings, shapes);
+            assert_eq!(key.board_width, tetris.board.width, "{}", shapes.name);
+            assert_eq!(key.board_height, tetris.board.height, "{}", shapes.name);
+        }
+    }
+}
@@ -0,0 +1,598 @@
+use crate::config::Settings;
+use crate::input::{Action, ActionEvents};
+use crate::{theme, CenterText, Layout, Mode};
+use graphics::text::Text;
+use graphics::{Context, Transformed};
+use opengl_graphics::{GlGraphics, GlyphCache};
+use piston::input::{keyboard::Key, Button, GenericEvent};
+
+/// Navigation through a menu, from either the keyboard or a controller.
+#[derive(Clone, Copy, PartialEq)]
+pub enum MenuInput {
+    Up,
+    Down,
+    Left,
+    Right,
+    Select,
+    Back,
+}
+
+impl MenuInput {
+    /// The arrow keys, Enter, Space, Backspace and Escape always work in
+    /// menus. Controllers navigate with whatever their movement, hard drop
+    /// and rotation actions are bound to.
+    pub fn read<E: GenericEvent>(e: &E, actions: &ActionEvents) -> Option<MenuInput> {
+        match e.press_args() {
+            Some(Button::Keyboard(key)) => match key {
+                Key::Up => Some(MenuInput::Up),
+                Key::Down => Some(MenuInput::Down),
+                Key::Left => Some(MenuInput::Left),
+                Key::Right => Some(MenuInput::Right),
+                Key::Return | Key::Space => Some(MenuInput::Select),
+                Key::Escape | Key::Backspace => Some(MenuInput::Back),
+                _ => None,
+            },
+            _ => actions.pressed.iter().find_map(|action| match action {
+                Action::HardDrop => Some(MenuInput::Up),
+                Action::SoftDrop => Some(MenuInput::Down),
+                Action::MoveLeft => Some(MenuInput::Left),
+                Action::MoveRight => Some(MenuInput::Right),
+                Action::RotateCw => Some(MenuInput::Select),
+                Action::RotateCcw => Some(MenuInput::Back),
+                _ => None,
+            }),
+        }
+    }
+}
+
+pub struct MenuItem {
+    pub label: String,
+    /// Shown after the label. Items with a value can be changed with left
+    /// and right.
+    pub value: Option<String>,
+}
+
+impl MenuItem {
+    pub fn new(label: &str) -> MenuItem {
+        MenuItem {
+            label: label.to_string(),
+            value: None,
+        }
+    }
+
+    pub fn with_value(label: &str, value: String) -> MenuItem {
+        MenuItem {
+            value: Some(value),
+            ..MenuItem::new(label)
+        }
+    }
+}
+
+/// What the player did to a menu.
+#[derive(Clone, Copy, PartialEq)]
+pub enum MenuEvent {
+    Select(usize),
+    /// Left or right on an item with a value.
+    Change(usize, isize),
+    Back,
+}
+
+/// A titled, vertical list of items with one of them focused.
+pub struct Menu {
+    pub title: String,
+    pub items: Vec<MenuItem>,
+    selected: usize,
+}
+
+impl Menu {
+    pub fn new(title: &str, items: Vec<MenuItem>) -> Menu {
+        Menu {
+            title: title.to_string(),
+            items,
+            selected: 0,
+        }
+    }
+
+    pub fn handle(&mut self, input: MenuInput) -> Option<MenuEvent> {
+        match input {
+            MenuInput::Up => self.move_focus(self.items.len().saturating_sub(1)),
+            MenuInput::Down => self.move_focus(1),
+            MenuInput::Left | MenuInput::Right => {
+                let item = self.items.get(self.selected)?;
+                if item.value.is_some() {
+                    let step = if input == MenuInput::Left { -1 } else { 1 };
+                    return Some(MenuEvent::Change(self.selected, step));
+                }
+            }
+            MenuInput::Select => {
+                self.items.get(self.selected)?;
+                return Some(MenuEvent::Select(self.selected));
+            }
+            MenuInput::Back => return Some(MenuEvent::Back),
+        }
+        None
+    }
+
+    // Steps forward by `step` places, wrapping.
+    fn move_focus(&mut self, step: usize) {
+        let count = self.items.len();
+        if count > 0 {
+            self.selected = (self.selected + step) % count;
+        }
+    }
+
+    /// Handles the event and draws the menu when it is time to render.
+    pub fn run<E: GenericEvent>(
+        &mut self,
+        e: &E,
+        actions: &ActionEvents,
+        layout: &Layout,
+        gl: &mut GlGraphics,
+        glyphs: &mut GlyphCache,
+    ) -> Option<MenuEvent> {
+        let event = MenuInput::read(e, actions).and_then(|input| self.handle(input));
+        if let Some(args) = e.render_args() {
+            gl.draw(args.viewport(), |c, g| {
+                graphics::clear(layout.theme.background, g);
+                let c = layout.fit(c, args.window_size);
+                self.draw(layout, &c, g, glyphs);
+            });
+        }
+        event
+    }
+
+    pub fn draw(&self, layout: &Layout, c: &Context, g: &mut GlGraphics, glyphs: &mut GlyphCache) {
+        let theme = &layout.theme;
+        let center = layout.width / 2.0;
+        let mut font = Text::new(60);
+        font.color = theme.text_shadow;
+        font.draw_center(
+            &self.title,
+            glyphs,
+            &c.draw_state,
+            c.transform.trans(center, 80.0),
+            g,
+        )
+        .expect("Unable to draw string");
+        font.color = theme.text;
+        font.draw_center(
+            &self.title,
+            glyphs,
+            &c.draw_state,
+            c.transform.trans(center - 2.0, 78.0),
+            g,
+        )
+        .expect("Unable to draw string");
+        font.font_size = 22;
+        let spacing = ((layout.height - 220.0) / self.items.len() as f64).min(32.0);
+        for (i, item) in self.items.iter().enumerate() {
+            let mut text = match &item.value {
+                Some(value) => format!("{}: {}", item.label, value),
+                None => item.label.clone(),
+            };
+            font.color = theme.text_shadow;
+            if i == self.selected {
+                text = format!("> {} <", text);
+                font.color = theme.text;
+            }
+            font.draw_center(
+                &text,
+                glyphs,
+                &c.draw_state,
+                c.transform.trans(center, 150.0 + i as f64 * spacing),
+                g,
+            )
+            .expect("Unable to draw string");
+        }
+        font.font_size = 16;
+        font.color = theme.text_shadow;
+        font.draw_center(
+            "Arrows: move  Enter: select  Esc: back",
+            glyphs,
+            &c.draw_state,
+            c.transform.trans(center, layout.height - 30.0),
+            g,
+        )
+        .expect("Unable to draw string");
+    }
+}
+
+/// Where the main menu can take the player.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Choice {
+    Play(Mode),
+    Settings,
+    KeyBindings,
+    HighScores,
+    Statistics,
+    Quit,
+}
+
+const MAIN_MENU: [(&str, Choice); 8] = [
+    ("Marathon", Choice::Play(Mode::Marathon)),
+    ("Finesse training", Choice::Play(Mode::FinesseTraining)),
+    ("Pentominoes", Choice::Play(Mode::Pentomino)),
+    ("Settings", Choice::Settings),
+    ("Key bindings", Choice::KeyBindings),
+    ("High scores", Choice::HighScores),
+    ("Statistics", Choice::Statistics),
+    ("Quit", Choice::Quit),
+];
+
+pub struct MainMenu {
+    menu: Menu,
+}
+
+impl Default for MainMenu {
+    fn default() -> MainMenu {
+        let items = MAIN_MENU
+            .iter()
+            .map(|&(label, _)| MenuItem::new(label))
+            .collect();
+        MainMenu {
+            menu: Menu::new("Tetris", items),
+        }
+    }
+}
+
+impl MainMenu {
+    pub fn run<E: GenericEvent>(
+        &mut self,
+        e: &E,
+        actions: &ActionEvents,
+        layout: &Layout,
+        gl: &mut GlGraphics,
+        glyphs: &mut GlyphCache,
+    ) -> Option<Choice> {
+        match self.menu.run(e, actions, layout, gl, glyphs)? {
+            MenuEvent::Select(i) => Some(MAIN_MENU[i].1),
+            MenuEvent::Change(..) => None,
+            MenuEvent::Back => Some(Choice::Quit),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Setting {
+    Theme,
+    Hold,
+    RandomSpawnRotation,
+    LockOut,
+    AutoRepeat,
+    SoftDrop,
+    Fullscreen,
+    BlockPatterns,
+    Effects,
+    Volume,
+    MusicVolume,
+    Mute,
+    Assists,
+    Gravity,
+    LockDelay,
+    GravityAfterDrop,
+    HighContrast,
+    Back,
+}
+
+const SETTINGS: [Setting; 13] = [
+    Setting::Theme,
+    Setting::Hold,
+    Setting::RandomSpawnRotation,
+    Setting::LockOut,
+    Setting::AutoRepeat,
+    Setting::SoftDrop,
+    Setting::Fullscreen,
+    Setting::Effects,
+    Setting::Volume,
+    Setting::MusicVolume,
+    Setting::Mute,
+    Setting::Assists,
+    Setting::Back,
+];
+
+const ASSISTS: [Setting; 6] = [
+    Setting::Gravity,
+    Setting::LockDelay,
+    Setting::GravityAfterDrop,
+    Setting::HighContrast,
+    Setting::BlockPatterns,
+    Setting::Back,
+];
+
+fn on_off(value: bool) -> String {
+    if value { "On" } else { "Off" }.to_string()
+}
+
+// Steps a delay by 10ms, keeping it within what the settings file allows.
+fn step_ms(ms: u64, step: isize) -> u64 {
+    (ms as i64 + step as i64 * 10).clamp(10, 1000) as u64
+}
+
+fn step_percent(percent: u32, step: isize) -> u32 {
+    (percent as i64 + step as i64 * 10).clamp(0, 100) as u32
+}
+
+// Gravity only slows down, and lock delay only gets longer.
+fn step_gravity(percent: u32, step: isize) -> u32 {
+    step_percent(percent, step).max(10)
+}
+
+fn step_lock_delay(percent: u32, step: isize) -> u32 {
+    (percent as i64 + step as i64 * 50).clamp(100, 1000) as u32
+}
+
+/// Edits the settings that make sense to change between games. The caller
+/// saves them and reloads anything built from them once the player is done.
+/// Assists have a page of their own.
+pub struct SettingsMenu {
+    menu: Menu,
+    assists: bool,
+}
+
+impl SettingsMenu {
+    pub fn new(settings: &Settings) -> SettingsMenu {
+        let mut menu = SettingsMenu {
+            menu: Menu::new("Settings", Vec::new()),
+            assists: false,
+        };
+        menu.refresh(settings);
+        menu
+    }
+
+    fn open_assists(&mut self, settings: &Settings) {
+        self.menu = Menu::new("Assists", Vec::new());
+        self.assists = true;
+        self.refresh(settings);
+    }
+
+    // Back to the main page, with the assists entry focused.
+    fn close_assists(&mut self, settings: &Settings) {
+        *self = SettingsMenu::new(settings);
+        let assists = SETTINGS
+            .iter()
+            .position(|setting| matches!(setting, Setting::Assists));
+        self.menu.selected = assists.unwrap_or(0);
+    }
+
+    fn page(&self) -> &'static [Setting] {
+        if self.assists {
+            &ASSISTS
+        } else {
+            &SETTINGS
+        }
+    }
+
+    fn refresh(&mut self, settings: &Settings) {
+        self.menu.items = self
+            .page()
+            .iter()
+            .map(|setting| match setting {
+                Setting::Theme => MenuItem::with_value("Theme", settings.display.theme.clone()),
+                Setting::Hold => MenuItem::with_value("Hold", on_off(settings.ruleset.hold)),
+                Setting::RandomSpawnRotation => MenuItem::with_value(
+                    "Random spawn rotation",
+                    on_off(settings.ruleset.random_spawn_rotation),
+                ),
+                Setting::LockOut => {
+                    MenuItem::with_value("Lock out", on_off(settings.ruleset.lock_out))
+                }
+                Setting::AutoRepeat => MenuItem::with_value(
+                    "Auto repeat",
+                    format!("{} ms", settings.handling.auto_repeat_ms),
+                ),
+                Setting::SoftDrop => MenuItem::with_value(
+                    "Soft drop",
+                    format!("{} ms", settings.handling.soft_drop_ms),
+                ),
+                Setting::Fullscreen => {
+                    MenuItem::with_value("Start fullscreen", on_off(settings.display.fullscreen))
+                }
+                Setting::BlockPatterns => {
+                    MenuItem::with_value("Block patterns", on_off(settings.display.block_patterns))
+                }
+                // Each effect can be turned off on its own in the settings
+                // file.
+                Setting::Effects => {
+                    let effects = &settings.effects;
+                    let value = if effects.all() {
+                        "On"
+                    } else if effects.any() {
+                        "Some"
+                    } else {
+                        "Off"
+                    };
+                    MenuItem::with_value("Effects", value.to_string())
+                }
+                Setting::Volume => {
+                    MenuItem::with_value("Volume", format!("{}%", settings.audio.volume))
+                }
+                Setting::MusicVolume => MenuItem::with_value(
+                    "Music volume",
+                    format!("{}%", settings.audio.music_volume),
+                ),
+                Setting::Mute => MenuItem::with_value("Mute", on_off(settings.audio.mute)),
+                Setting::Assists => MenuItem::new("Assists"),
+                Setting::Gravity => {
+                    MenuItem::with_value("Gravity", format!("{}%", settings.assist.gravity_percent))
+                }
+                Setting::LockDelay => MenuItem::with_value(
+                    "Lock delay",
+                    format!("{}%", settings.assist.lock_delay_percent),
+                ),
+                Setting::GravityAfterDrop => MenuItem::with_value(
+                    "Wait for first drop",
+                    on_off(settings.assist.gravity_after_drop),
+                ),
+                Setting::HighContrast => {
+                    MenuItem::with_value("High contrast", on_off(settings.assist.high_contrast))
+                }
+                Setting::Back => MenuItem::new("Back"),
+            })
+            .collect();
+    }
+
+    /// Returns true once the player leaves the menu.
+    pub fn run<E: GenericEvent>(
+        &mut self,
+        e: &E,
+        actions: &ActionEvents,
+        settings: &mut Settings,
+        layout: &Layout,
+        gl: &mut GlGraphics,
+        glyphs: &mut GlyphCache,
+    ) -> bool {
+        let (i, step) = match self.menu.run(e, actions, layout, gl, glyphs) {
+            Some(MenuEvent::Select(i)) => (i, 1),
+            Some(MenuEvent::Change(i, step)) => (i, step),
+            Some(MenuEvent::Back) => return self.back(settings),
+            None => return false,
+        };
+        match self.page()[i] {
+            Setting::Theme => {
+                // Themes from files are kept until the player picks another.
+                let names: Vec<&str> = theme::BUILT_IN.iter().map(|&(name, _)| name).collect();
+                let next = match names
+                    .iter()
+                    .position(|&name| name == settings.display.theme)
+                {
+                    Some(current) => (current as isize + step).rem_euclid(names.len() as isize),
+                    None => 0,
+                };
+                settings.display.theme = names[next as usize].to_string();
+            }
+            Setting::Hold => settings.ruleset.hold = !settings.ruleset.hold,
+            Setting::RandomSpawnRotation => {
+                settings.ruleset.random_spawn_rotation = !settings.ruleset.random_spawn_rotation
+            }
+            Setting::LockOut => settings.ruleset.lock_out = !settings.ruleset.lock_out,
+            Setting::AutoRepeat => {
+                settings.handling.auto_repeat_ms = step_ms(settings.handling.auto_repeat_ms, step)
+            }
+            Setting::SoftDrop => {
+                settings.handling.soft_drop_ms = step_ms(settings.handling.soft_drop_ms, step)
+            }
+            Setting::Fullscreen => settings.display.fullscreen = !settings.display.fullscreen,
+            Setting::BlockPatterns => {
+                settings.display.block_patterns = !settings.display.block_patterns
+            }
+            Setting::Effects => {
+                let on = !settings.effects.all();
+                settings.effects.set_all(on);
+            }
+            Setting::Volume => settings.audio.volume = step_percent(settings.audio.volume, step),
+            Setting::MusicVolume => {
+                settings.audio.music_volume = step_percent(settings.audio.music_volume, step)
+            }
+            Setting::Mute => settings.audio.mute = !settings.audio.mute,
+            Setting::Assists => {
+                self.open_assists(settings);
+                return false;
+            }
+            Setting::Gravity => {
+                settings.assist.gravity_percent =
+                    step_gravity(settings.assist.gravity_percent, step)
+            }
+            Setting::LockDelay => {
+                settings.assist.lock_delay_percent =
+                    step_lock_delay(settings.assist.lock_delay_percent, step)
+            }
+            Setting::GravityAfterDrop => {
+                settings.assist.gravity_after_drop = !settings.assist.gravity_after_drop
+            }
+            Setting::HighContrast => settings.assist.high_contrast = !settings.assist.high_contrast,
+            Setting::Back => return self.back(settings),
+        }
+        self.refresh(settings);
+        false
+    }
+
+    // Leaving the assists page goes back to the main one.
+    fn back(&mut self, settings: &Settings) -> bool {
+        if !self.assists {
+            return true;
+        }
+        self.close_assists(settings);
+        false
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum PauseChoice {
+    Resume,
+    Restart,
+    Settings,
+    QuitToMenu,
+    QuitGame,
+}
+
+const PAUSE_MENU: [(&str, PauseChoice); 5] = [
+    ("Resume", PauseChoice::Resume),
+    ("Restart", PauseChoice::Restart),
+    ("Settings", PauseChoice::Settings),
+    ("Quit to menu", PauseChoice::QuitToMenu),
+    ("Quit game", PauseChoice::QuitGame),
+];
+
+/// Shown while a game is paused. Only picking an item leaves it, so a
+/// stray key press can't unpause the game.
+pub struct PauseMenu {
+    menu: Menu,
+}
+
+impl Default for PauseMenu {
+    fn default() -> PauseMenu {
+        let items = PAUSE_MENU
+            .iter()
+            .map(|&(label, _)| MenuItem::new(label))
+            .collect();
+        PauseMenu {
+            menu: Menu::new("Paused", items),
+        }
+    }
+}
+
+impl PauseMenu {
+    pub fn run<E: GenericEvent>(
+        &mut self,
+        e: &E,
+        actions: &ActionEvents,
+        layout: &Layout,
+        gl: &mut GlGraphics,
+        glyphs: &mut GlyphCache,
+    ) -> Option<PauseChoice> {
+        match self.menu.run(e, actions, layout, gl, glyphs)? {
+            MenuEvent::Select(i) => Some(PAUSE_MENU[i].1),
+            MenuEvent::Change(..) => None,
+            MenuEvent::Back => Some(PauseChoice::Resume),
+        }
+    }
+}
+
+/// A yes or no question. Backing out answers no, which is also where the
+/// focus starts.
+pub struct Confirm {
+    menu: Menu,
+}
+
+impl Confirm {
+    pub fn new(question: &str, yes: &str, no: &str) -> Confirm {
+        Confirm {
+            menu: Menu::new(question, vec![MenuItem::new(no), MenuItem::new(yes)]),
+        }
+    }
+
+    pub fn run<E: GenericEvent>(
+        &mut self,
+        e: &E,
+        actions: &ActionEvents,
+        layout: &Layout,
+        gl: &mut GlGraphics,
+        glyphs: &mut GlyphCache,
+    ) -> Option<bool> {
+        match self.menu.run(e, actions, layout, gl, glyphs)? {
+            MenuEvent::Select(i) => Some(i == 1),
+            MenuEvent::Change(..) => None,
+            MenuEvent::Back => Some(false),
+        }
+    }
+}
@@ -0,0 +1,251 @@
+use crate::{Board, Piece, Rotation};
+use std::collections::{HashMap, VecDeque};
+
+/// A single player input, as understood by `Tetris::run`.
+///
+/// `DasLeft`, `DasRight` and `SoftDrop` stand for holding the key until the
+/// piece stops moving, which is how a player reaches the walls or the floor
+/// with one press. `Down` is a single tap of soft drop, for stopping partway
+/// down to slide into a gap in the side of a well.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Input {
+    Left,
+    Right,
+    DasLeft,
+    DasRight,
+    RotateCw,
+    RotateCcw,
+    Down,
+    SoftDrop,
+    HardDrop,
+}
+
+impl Input {
+    /// Whether the input counts towards finesse. Drops don't, since how far
+    /// a piece falls before locking is up to the player and gravity.
+    pub fn counts(self) -> bool {
+        !matches!(self, Input::Down | Input::SoftDrop | Input::HardDrop)
+    }
+}
+
+/// The number of inputs in a path that count towards finesse.
+pub fn cost(path: &[Input]) -> u32 {
+    path.iter().filter(|input| input.counts()).count() as u32
+}
+
+const INPUTS: [Input; 8] = [
+    Input::Left,
+    Input::Right,
+    Input::DasLeft,
+    Input::DasRight,
+    Input::RotateCw,
+    Input::RotateCcw,
+    Input::Down,
+    Input::SoftDrop,
+];
+
+/// Where a piece ends up once it locks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Placement {
+    pub x: isize,
+    pub y: isize,
+    pub rotation: usize,
+}
+
+impl Placement {
+    pub fn of(piece: &Piece) -> Placement {
+        Placement {
+            x: piece.x,
+            y: piece.y,
+            rotation: piece.rotation,
+        }
+    }
+
+    fn apply(&self, piece: &mut Piece) {
+        piece.x = self.x;
+        piece.y = self.y;
+        piece.rotation = self.rotation;
+    }
+}
+
+/// Applies one input to the piece using the same movement and rotation rules
+/// as the game loop. Returns false when the input has no effect.
+pub fn apply(board: &Board, piece: &mut Piece, input: Input) -> bool {
+    let before = Placement::of(piece);
+    match input {
+        Input::Left => {
+            board.try_move(piece, -1, 0);
+        }
+        Input::Right => {
+            board.try_move(piece, 1, 0);
+        }
+        Input::DasLeft => board.slide(piece, -1, 0),
+        Input::DasRight => board.slide(piece, 1, 0),
+        Input::RotateCw => {
+            board.try_rotate(piece, Rotation::Cw);
+        }
+        Input::RotateCcw => {
+            board.try_rotate(piece, Rotation::Ccw);
+        }
+        Input::Down => {
+            board.try_move(piece, 0, 1);
+        }
+        Input::SoftDrop | Input::HardDrop => board.slide(piece, 0, 1),
+    }
+    Placement::of(piece) != before
+}
+
+/// Finds the input sequence with the lowest `cost` that takes `piece` from
+/// its current position to `target`, ending with a hard drop. Tucks, spins
+/// and slides under overhangs are found because every reachable state is
+/// explored, not just those directly above the target.
+pub fn find_path(board: &Board, piece: &Piece, target: Placement) -> Option<Vec<Input>> {
+    let start = Placement::of(piece);
+    // The cheapest known way to each state. Drops are free, so they go to
+    // the front of the queue and states come out in order of cost.
+    let mut costs: HashMap<Placement, u32> = HashMap::new();
+    let mut came_from: HashMap<Placement, (Placement, Input)> = HashMap::new();
+    let mut queue = VecDeque::new();
+    costs.insert(start, 0);
+    queue.push_back((start, 0));
+    let mut scratch = piece.clone();
+    while let Some((state, cost)) = queue.pop_front() {
+        if cost > costs[&state] {
+            continue;
+        }
+        state.apply(&mut scratch);
+        board.slide(&mut scratch, 0, 1);
+        if Placement::of(&scratch) == target {
+            let mut path = vec![Input::HardDrop];
+            let mut current = state;
+            while current != start {
+                let (previous, input) = came_from[&current];
+                path.push(input);
+                current = previous;
+            }
+            path.reverse();
+            return Some(path);
+        }
+        for &input in INPUTS.iter() {
+            state.apply(&mut scratch);
+            if !apply(board, &mut scratch, input) {
+                continue;
+            }
+            let next = Placement::of(&scratch);
+            let next_cost = cost + input.counts() as u32;
+            if costs.get(&next).is_some_and(|&known| known <= next_cost) {
+                continue;
+            }
+            costs.insert(next, next_cost);
+            came_from.insert(next, (state, input));
+            if input.counts() {
+                queue.push_back((next, next_cost));
+            } else {
+                queue.push_front((next, next_cost));
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::BoardSize;
+    use crate::{shapes, Block};
+
+    fn o_piece(board: &Board) -> Piece {
+        let set = shapes::load(None).unwrap();
+        let shape = set.shapes.iter().find(|shape| shape.name() == "O").unwrap();
+        let mut piece = Piece {
+            shape: shape.clone(),
+            rotation: 0,
+            x: 0,
+            y: 0,
+            color: 0,
+        };
+        board.move_to_spawn(&mut piece);
+        piece
+    }
+
+    // Fills columns 2 and up of the given rows, then the pocket's right
+    // side in `walled` rows, leaving a two wide well down the left wall.
+    fn board_with_pocket(overhang: isize, walled: &[isize], floor: &[isize]) -> Board {
+        let mut board = Board::new(&BoardSize::default());
+        let block = Block {
+            color: 0,
+            shape: None,
+            links: 0,
+        };
+        let buffer = board.buffer as isize;
+        let mut fill = |x: usize, y: isize| board.cells[x][(y + buffer) as usize] = Some(block);
+        for x in 2..10 {
+            fill(x, overhang);
+            for &y in floor {
+                fill(x, y);
+            }
+        }
+        for x in 4..10 {
+            for &y in walled {
+                fill(x, y);
+            }
+        }
+        board
+    }
+
+    fn follow(board: &Board, piece: &Piece, path: &[Input]) -> Placement {
+        let mut piece = piece.clone();
+        for &input in path {
+            apply(board, &mut piece, input);
+        }
+        Placement::of(&piece)
+    }
+
+    #[test]
+    fn slides_under_an_overhang() {
+        let board = board_with_pocket(17, &[18, 19], &[]);
+        let piece = o_piece(&board);
+        // The O covers columns 2-3 of rows 18-19.
+        let target = Placement {
+            x: 1,
+            y: 16,
+            rotation: 0,
+        };
+        let path = find_path(&board, &piece, target).unwrap();
+        assert_eq!(path.last(), Some(&Input::HardDrop));
+        assert_eq!(cost(&path), 2);
+        assert_eq!(follow(&board, &piece, &path), target);
+    }
+
+    #[test]
+    fn stops_partway_down_to_tuck() {
+        let board = board_with_pocket(14, &[15, 16], &[17, 18, 19]);
+        let piece = o_piece(&board);
+        // The pocket is halfway down the well, so a soft drop would pass it.
+        let target = Placement {
+            x: 1,
+            y: 13,
+            rotation: 0,
+        };
+        let path = find_path(&board, &piece, target).unwrap();
+        assert!(path.contains(&Input::Down));
+        assert_eq!(cost(&path), 2);
+        assert_eq!(follow(&board, &piece, &path), target);
+    }
+
+    #[test]
+    fn sealed_pocket_is_unreachable() {
+        let mut board = board_with_pocket(17, &[18, 19], &[]);
+        let buffer = board.buffer as isize;
+        for x in 0..2 {
+            board.cells[x][(17 + buffer) as usize] = board.get(2, 17);
+        }
+        let piece = o_piece(&board);
+        let target = Placement {
+            x: 1,
+            y: 16,
+            rotation: 0,
+        };
+        assert_eq!(find_path(&board, &piece, target), None);
+    }
+}
@@ -0,0 +1,415 @@
+use crate::config::{self, ConfigError, Ruleset, Settings};
+use crate::input::ActionEvents;
+use crate::menu::MenuInput;
+use crate::shapes::ShapeSet;
+use crate::{CenterText, Layout, Mode, MODES};
+use graphics::text::Text;
+use graphics::Transformed;
+use opengl_graphics::{GlGraphics, GlyphCache};
+use piston::input::{keyboard::Key, Button, GenericEvent};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const TABLE_SIZE: usize = 10;
+const MAX_NAME_LENGTH: usize = 12;
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Entry {
+    pub name: String,
+    pub score: u32,
+    pub lines: u32,
+    pub level: u32,
+    /// Time played, leaving out pauses.
+    pub time_ms: u64,
+    /// Seconds since the Unix epoch.
+    pub date: u64,
+    /// Deals the same pieces again.
+    pub seed: u32,
+}
+
+/// Seconds since the Unix epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0)
+}
+
+/// What a game's score is compared on. Only games with the same mode,
+/// rules, shapes and board share a table, and assisted games are kept apart.
+#[derive(Clone)]
+pub struct TableKey {
+    pub mode: Mode,
+    pub ruleset: Ruleset,
+    pub assisted: bool,
+    /// The name of the shape set.
+    pub shapes: String,
+    pub board_width: usize,
+    pub board_height: usize,
+}
+
+impl TableKey {
+    pub fn new(mode: Mode, settings: &Settings, shapes: &ShapeSet) -> TableKey {
+        TableKey {
+            mode,
+            ruleset: settings.ruleset.clone(),
+            assisted: settings.assist.in_use(),
+            shapes: shapes.name.clone(),
+            // Shape sets can ask for their own board width, as in `Tetris::new`.
+            board_width: shapes.board_width.unwrap_or(settings.board.width),
+            board_height: settings.board.height,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct Table {
+    mode: Mode,
+    ruleset: Ruleset,
+    #[serde(default)]
+    assisted: bool,
+    #[serde(default)]
+    shapes: String,
+    #[serde(default)]
+    board_width: usize,
+    #[serde(default)]
+    board_height: usize,
+    entries: Vec<Entry>,
+}
+
+impl Table {
+    fn is_for(&self, key: &TableKey) -> bool {
+        self.mode == key.mode
+            && self.ruleset == key.ruleset
+            && self.assisted == key.assisted
+            && self.shapes == key.shapes
+            && self.board_width == key.board_width
+            && self.board_height == key.board_height
+    }
+}
+
+/// The best games for each table key, as stored in
+/// `highscores.toml` in the user data directory.
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct HighScores {
+    /// Offered again on the next name entry.
+    pub last_name: String,
+    tables: Vec<Table>,
+}
+
+impl HighScores {
+    fn path() -> Option<PathBuf> {
+        dirs::data_dir().map(|dir| dir.join("tetris").join("highscores.toml"))
+    }
+
+    /// Reads the high score file, starting empty when there is none.
+    pub fn load() -> Result<HighScores, ConfigError> {
+        config::load_file(HighScores::path())
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        config::save_file(self, HighScores::path())
+    }
+
+    pub fn entries(&self, key: &TableKey) -> &[Entry] {
+        self.tables
+            .iter()
+            .find(|table| table.is_for(key))
+            .map_or(&[], |table| &table.entries)
+    }
+
+    pub fn qualifies(&self, key: &TableKey, score: u32) -> bool {
+        let entries = self.entries(key);
+        score > 0 && (entries.len() < TABLE_SIZE || entries.iter().any(|entry| score > entry.score))
+    }
+
+    /// Adds the entry below any equal scores and returns its place in the
+    /// table, or None if it didn't make it.
+    pub fn insert(&mut self, key: &TableKey, entry: Entry) -> Option<usize> {
+        if !self.qualifies(key, entry.score) {
+            return None;
+        }
+        self.last_name = entry.name.clone();
+        let index = match self.tables.iter().position(|table| table.is_for(key)) {
+            Some(index) => index,
+            None => {
+                self.tables.push(Table {
+                    mode: key.mode,
+                    ruleset: key.ruleset.clone(),
+                    assisted: key.assisted,
+                    shapes: key.shapes.clone(),
+                    board_width: key.board_width,
+                    board_height: key.board_height,
+                    entries: Vec::new(),
+                });
+                self.tables.len() - 1
+            }
+        };
+        let entries = &mut self.tables[index].entries;
+        let place = entries
+            .iter()
+            .position(|other| entry.score > other.score)
+            .unwrap_or(entries.len());
+        entries.insert(place, entry);
+        entries.truncate(TABLE_SIZE);
+        Some(place)
+    }
+}
+
+fn format_time(ms: u64) -> String {
+    let secs = ms / 1000;
+    format!("{}:{:02}", secs / 60, secs % 60)
+}
+
+// Converts days since the epoch to a calendar date, after Howard Hinnant's
+// `civil_from_days`.
+fn format_date(secs: u64) -> String {
+    let days = (secs / 86400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{}-{:02}-{:02}", year, month, day)
+}
+
+/// Asks for a name after a game good enough for the table.
+pub struct NameEntry {
+    pub name: String,
+    score: u32,
+}
+
+impl NameEntry {
+    pub fn new(last_name: &str, score: u32) -> NameEntry {
+        NameEntry {
+            name: last_name.to_string(),
+            score,
+        }
+    }
+
+    /// Returns whether to keep the score once the player is done.
+    pub fn run<E: GenericEvent>(
+        &mut self,
+        e: &E,
+        actions: &ActionEvents,
+        layout: &Layout,
+        gl: &mut GlGraphics,
+        glyphs: &mut GlyphCache,
+    ) -> Option<bool> {
+        match e.press_args() {
+            Some(Button::Keyboard(Key::Return)) => return Some(true),
+            Some(Button::Keyboard(Key::Escape)) => return Some(false),
+            Some(Button::Keyboard(Key::Backspace)) => {
+                self.name.pop();
+            }
+            // Typed characters arrive as text events.
+            Some(Button::Keyboard(_)) => {}
+            _ => match MenuInput::read(e, actions) {
+                Some(MenuInput::Select) => return Some(true),
+                Some(MenuInput::Back) => return Some(false),
+                _ => {}
+            },
+        }
+        if let Some(text) = e.text_args() {
+            for c in text.chars().filter(|c| !c.is_control()) {
+                if self.name.chars().count() < MAX_NAME_LENGTH {
+                    self.name.push(c);
+                }
+            }
+        }
+        if let Some(args) = e.render_args() {
+            gl.draw(args.viewport(), |c, g| {
+                let theme = &layout.theme;
+                graphics::clear(theme.background, g);
+                let c = layout.fit(c, args.window_size);
+                let center = layout.width / 2.0;
+                let mut font = Text::new_color(theme.text, 50);
+                font.draw_center(
+                    "New High Score",
+                    glyphs,
+                    &c.draw_state,
+                    c.transform.trans(center, 100.0),
+                    g,
+                )
+                .expect("Unable to draw string");
+                font.font_size = 24;
+                font.color = theme.text_shadow;
+                font.draw_center(
+                    &format!("Score: {}", self.score),
+                    glyphs,
+                    &c.draw_state,
+                    c.transform.trans(center, 170.0),
+                    g,
+                )
+                .expect("Unable to draw string");
+                font.draw_center(
+                    "Name:",
+                    glyphs,
+                    &c.draw_state,
+                    c.transform.trans(center, 240.0),
+                    g,
+                )
+                .expect("Unable to draw string");
+                font.color = theme.text;
+                font.draw_center(
+                    &format!("{}_", self.name),
+                    glyphs,
+                    &c.draw_state,
+                    c.transform.trans(center, 280.0),
+                    g,
+                )
+                .expect("Unable to draw string");
+                font.font_size = 16;
+                font.color = theme.text_shadow;
+                font.draw_center(
+                    "Enter: save  Esc: skip",
+                    glyphs,
+                    &c.draw_state,
+                    c.transform.trans(center, layout.height - 30.0),
+                    g,
+                )
+                .expect("Unable to draw string");
+            });
+        }
+        None
+    }
+}
+
+/// Shows the table for one mode at a time under the current settings, with
+/// assisted games on a table of their own.
+pub struct HighScoreScreen {
+    mode: usize,
+    /// The table for each of `MODES` under the current settings.
+    keys: Vec<TableKey>,
+    assisted: bool,
+}
+
+impl HighScoreScreen {
+    pub fn new(mode: Mode, keys: Vec<TableKey>) -> HighScoreScreen {
+        let mode = MODES.iter().position(|&other| other == mode).unwrap_or(0);
+        let assisted = keys.get(mode).is_some_and(|key| key.assisted);
+        HighScoreScreen {
+            mode,
+            keys,
+            assisted,
+        }
+    }
+
+    /// Returns true once the player leaves.
+    pub fn run<E: GenericEvent>(
+        &mut self,
+        e: &E,
+        actions: &ActionEvents,
+        scores: &HighScores,
+        layout: &Layout,
+        gl: &mut GlGraphics,
+        glyphs: &mut GlyphCache,
+    ) -> bool {
+        match MenuInput::read(e, actions) {
+            Some(MenuInput::Select) | Some(MenuInput::Back) => return true,
+            Some(MenuInput::Left) => {
+                self.mode = (self.mode + MODES.len() - 1) % MODES.len();
+            }
+            Some(MenuInput::Right) => {
+                self.mode = (self.mode + 1) % MODES.len();
+            }
+            Some(MenuInput::Up) | Some(MenuInput::Down) => {
+                self.assisted = !self.assisted;
+            }
+            None => {}
+        }
+        if let Some(args) = e.render_args() {
+            gl.draw(args.viewport(), |c, g| {
+                let theme = &layout.theme;
+                graphics::clear(theme.background, g);
+                let c = layout.fit(c, args.window_size);
+                let center = layout.width / 2.0;
+                let mode = MODES[self.mode];
+                let mut font = Text::new_color(theme.text, 40);
+                font.draw_center(
+                    "High Scores",
+                    glyphs,
+                    &c.draw_state,
+                    c.transform.trans(center, 60.0),
+                    g,
+                )
+                .expect("Unable to draw string");
+                font.font_size = 20;
+                let heading = if self.assisted {
+                    format!("< {} (assisted) >", mode.label())
+                } else {
+                    format!("< {} >", mode.label())
+                };
+                font.draw_center(
+                    &heading,
+                    glyphs,
+                    &c.draw_state,
+                    c.transform.trans(center, 100.0),
+                    g,
+                )
+                .expect("Unable to draw string");
+                font.font_size = 16;
+                let columns = [0.06, 0.12, 0.42, 0.54, 0.65, 0.74, 0.84];
+                let headings = ["#", "Name", "Score", "Lines", "Level", "Time", "Date"];
+                let mut draw_row = |cells: &[String], y: f64, font: &Text| {
+                    for (cell, column) in cells.iter().zip(columns.iter()) {
+                        font.draw(
+                            cell,
+                            glyphs,
+                            &c.draw_state,
+                            c.transform.trans(layout.width * column, y),
+                            g,
+                        )
+                        .expect("Unable to draw string");
+                    }
+                };
+                font.color = theme.text_shadow;
+                let headings: Vec<String> = headings.iter().map(|h| h.to_string()).collect();
+                draw_row(&headings, 140.0, &font);
+                let key = TableKey {
+                    assisted: self.assisted,
+                    ..self.keys[self.mode].clone()
+                };
+                let entries = scores.entries(&key);
+                if entries.is_empty() {
+                    draw_row(&["".to_string(), "No scores yet".to_string()], 170.0, &font);
+                }
+                let spacing = ((layout.height - 230.0) / TABLE_SIZE as f64).min(26.0);
+                font.color = theme.text;
+                for (i, entry) in entries.iter().enumerate() {
+                    let cells = [
+                        (i + 1).to_string(),
+                        entry.name.clone(),
+                        entry.score.to_string(),
+                        entry.lines.to_string(),
+                        entry.level.to_string(),
+                        format_time(entry.time_ms),
+                        format_date(entry.date),
+                    ];
+                    draw_row(&cells, 170.0 + i as f64 * spacing, &font);
+                }
+                font.color = theme.text_shadow;
+                font.draw_center(
+                    "Left/Right: mode  Up/Down: assisted  Esc: back",
+                    glyphs,
+                    &c.draw_state,
+                    c.transform.trans(center, layout.height - 30.0),
+                    g,
+                )
+                .expect("Unable to draw string");
+            });
+        }
+        false
+    }
+}
@@ -0,0 +1,402 @@
+use crate::config::ConfigError;
+use crate::theme::Theme;
+use crate::{
+    BLANK, COLORS, DARKGRAY, KICKS, LIGHTCOLORS, LIGHTMIDGRAY, MIDGRAY, TEMPLATEHEIGHT,
+    TEMPLATEWIDTH, WHITE,
+};
+use graphics::types::Color;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use std::rc::Rc;
+
+const BLOCK: u8 = b'O';
+const TETROMINOES: &str = include_str!("../shapes/tetromino.toml");
+const PENTOMINOES: &str = include_str!("../shapes/pentomino.toml");
+
+pub type Template = [[u8; TEMPLATEWIDTH]; TEMPLATEHEIGHT];
+
+/// A piece shape with its rotation states in clockwise order.
+#[derive(Clone)]
+pub struct Shape {
+    name: String,
+    /// Position in the shape set.
+    pub index: usize,
+    rotations: Vec<Template>,
+    /// Offset from the board's default spawn position.
+    pub spawn: (isize, isize),
+    /// Palette entries a piece of this shape may be drawn in.
+    pub colors: Vec<usize>,
+    /// Offsets tried in order when a rotation is blocked.
+    pub kicks: Vec<(isize, isize)>,
+}
+
+impl Shape {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn template(&self) -> &[Template] {
+        &self.rotations
+    }
+}
+
+/// Border and fill colors for one palette entry.
+pub type BlockColors = (Color, Color);
+
+/// The shapes played with in a game and the colors they are drawn in.
+#[derive(Clone)]
+pub struct ShapeSet {
+    /// The file name without its extension, which themes refer to.
+    pub name: String,
+    pub shapes: Vec<Rc<Shape>>,
+    pub palette: Vec<BlockColors>,
+    /// Board width the set is meant for, overriding the settings.
+    pub board_width: Option<usize>,
+}
+
+impl ShapeSet {
+    /// The palette entry used for garbage, always the last one.
+    pub fn garbage(&self) -> usize {
+        self.palette.len() - 1
+    }
+
+    /// A copy of the set drawn in the theme's colors. Shapes the theme has
+    /// colors for always use them rather than picking from the palette.
+    pub fn with_theme(&self, theme: &Theme) -> ShapeSet {
+        let mut palette = self.palette[..self.garbage()].to_vec();
+        let shapes = self
+            .shapes
+            .iter()
+            .map(|shape| match theme.piece_colors(&self.name, shape.name()) {
+                Some(colors) => {
+                    palette.push(colors);
+                    let mut shape = Shape::clone(shape);
+                    shape.colors = vec![palette.len() - 1];
+                    Rc::new(shape)
+                }
+                None => shape.clone(),
+            })
+            .collect();
+        palette.push(theme.garbage);
+        ShapeSet {
+            name: self.name.clone(),
+            shapes,
+            palette,
+            board_width: self.board_width,
+        }
+    }
+
+    /// A copy of the set with every color at full brightness and outlined
+    /// in white. Garbage is dark so it stands apart from the pieces.
+    pub fn with_high_contrast(&self) -> ShapeSet {
+        let garbage = self.garbage();
+        let palette = self
+            .palette
+            .iter()
+            .enumerate()
+            .map(|(i, &(_, fill))| {
+                if i == garbage {
+                    return (WHITE, DARKGRAY);
+                }
+                let brightest = fill[0].max(fill[1]).max(fill[2]).max(0.01);
+                let mut bright = fill;
+                for part in bright.iter_mut().take(3) {
+                    *part /= brightest;
+                }
+                (WHITE, bright)
+            })
+            .collect();
+        ShapeSet {
+            palette,
+            ..self.clone()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ShapeFile {
+    palette: Option<Vec<(String, String)>>,
+    kicks: Option<Vec<(isize, isize)>>,
+    board_width: Option<usize>,
+    shape: Vec<ShapeData>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ShapeData {
+    name: String,
+    #[serde(default)]
+    spawn: (isize, isize),
+    colors: Option<Vec<usize>>,
+    kicks: Option<Vec<(isize, isize)>>,
+    rotations: Vec<Vec<String>>,
+}
+
+/// Reads a shape data file, or the standard tetrominoes without one.
+pub fn load(path: Option<&Path>) -> Result<ShapeSet, ConfigError> {
+    match path {
+        Some(path) => {
+            let contents = fs::read_to_string(path)
+                .map_err(|err| ConfigError::Read(path.to_path_buf(), err))?;
+            parse(&contents, path)
+        }
+        None => Ok(built_in(TETROMINOES, "tetromino.toml")),
+    }
+}
+
+/// The 18 one-sided pentominoes.
+pub fn pentominoes() -> ShapeSet {
+    built_in(PENTOMINOES, "pentomino.toml")
+}
+
+fn built_in(contents: &str, name: &str) -> ShapeSet {
+    parse(contents, Path::new(name))
+        .unwrap_or_else(|err| panic!("Built-in shapes are broken: {}", err))
+}
+
+fn parse(contents: &str, path: &Path) -> Result<ShapeSet, ConfigError> {
+    let file: ShapeFile =
+        toml::from_str(contents).map_err(|err| ConfigError::Parse(path.to_path_buf(), err))?;
+    let invalid = |reason: String| ConfigError::Invalid(path.to_path_buf(), reason);
+    if file.shape.is_empty() {
+        return Err(invalid("no shapes defined".to_string()));
+    }
+    let mut palette = match file.palette {
+        Some(palette) => {
+            let mut colors = Vec::new();
+            for (border, fill) in palette.iter() {
+                match (parse_color(border), parse_color(fill)) {
+                    (Some(border), Some(fill)) => colors.push((border, fill)),
+                    _ => {
+                        return Err(invalid(format!(
+                            "palette entry [{:?}, {:?}] is not two #rrggbb colors",
+                            border, fill
+                        )))
+                    }
+                }
+            }
+            colors
+        }
+        None => COLORS
+            .iter()
+            .cloned()
+            .zip(LIGHTCOLORS.iter().cloned())
+            .collect(),
+    };
+    if palette.is_empty() {
+        return Err(invalid("palette must not be empty".to_string()));
+    }
+    if let Some(width) = file.board_width {
+        if !(4..=50).contains(&width) {
+            return Err(invalid(format!(
+                "board_width must be between 4 and 50, not {}",
+                width
+            )));
+        }
+    }
+    let kicks = file.kicks.unwrap_or_else(|| KICKS.to_vec());
+    let mut names = HashSet::new();
+    let mut shapes = Vec::new();
+    for data in file.shape {
+        if !names.insert(data.name.clone()) {
+            return Err(invalid(format!("shape {} is defined twice", data.name)));
+        }
+        let shape = data
+            .into_shape(shapes.len(), palette.len(), &kicks)
+            .map_err(|(name, reason)| invalid(format!("shape {}: {}", name, reason)))?;
+        shapes.push(Rc::new(shape));
+    }
+    palette.push((MIDGRAY, LIGHTMIDGRAY));
+    Ok(ShapeSet {
+        name: path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        shapes,
+        palette,
+        board_width: file.board_width,
+    })
+}
+
+/// Parses a `#rrggbb` color.
+pub fn parse_color(hex: &str) -> Option<Color> {
+    let hex = hex.strip_prefix('#')?;
+    if hex.len() != 6 {
+        return None;
+    }
+    let channel = |i: usize| {
+        u8::from_str_radix(hex.get(i..i + 2)?, 16)
+            .ok()
+            .map(|value| value as f32 / 255.0)
+    };
+    Some([channel(0)?, channel(2)?, channel(4)?, 1.0])
+}
+
+impl ShapeData {
+    fn into_shape(
+        self,
+        index: usize,
+        palette_size: usize,
+        kicks: &[(isize, isize)],
+    ) -> Result<Shape, (String, String)> {
+        let name = self.name;
+        let fail = |reason: String| Err((name.clone(), reason));
+        if name.is_empty() {
+            return fail("name must not be empty".to_string());
+        }
+        if self.rotations.is_empty() {
+            return fail("needs at least one rotation".to_string());
+        }
+        let mut rotations = Vec::new();
+        for (i, rows) in self.rotations.iter().enumerate() {
+            match template(rows) {
+                Ok(template) => rotations.push(template),
+                Err(reason) => return fail(format!("rotation {}: {}", i, reason)),
+            }
+        }
+        let cells = normalized_cells(&rotations[0]);
+        if !is_connected(&cells) {
+            return fail("blocks must be connected".to_string());
+        }
+        for (i, current) in rotations.iter().enumerate() {
+            let next = &rotations[(i + 1) % rotations.len()];
+            if rotated_cw(&normalized_cells(current)) != normalized_cells(next) {
+                return fail(format!(
+                    "rotation {} is not rotation {} turned clockwise",
+                    (i + 1) % rotations.len(),
+                    i
+                ));
+            }
+        }
+        let colors = self.colors.unwrap_or_else(|| (0..palette_size).collect());
+        if colors.is_empty() {
+            return fail("colors must not be empty".to_string());
+        }
+        if let Some(color) = colors.iter().find(|&&color| color >= palette_size) {
+            return fail(format!(
+                "color {} is out of range, the palette has {} colors",
+                color, palette_size
+            ));
+        }
+        let kicks = self.kicks.unwrap_or_else(|| kicks.to_vec());
+        if !kicks.contains(&(0, 0)) {
+            return fail("kicks must include [0, 0]".to_string());
+        }
+        Ok(Shape {
+            name,
+            index,
+            rotations,
+            spawn: self.spawn,
+            colors,
+            kicks,
+        })
+    }
+}
+
+fn template(rows: &[String]) -> Result<Template, String> {
+    if rows.len() != TEMPLATEHEIGHT {
+        return Err(format!("needs {} rows, not {}", TEMPLATEHEIGHT, rows.len()));
+    }
+    let mut template = [[BLANK; TEMPLATEWIDTH]; TEMPLATEHEIGHT];
+    for (y, row) in rows.iter().enumerate() {
+        let row = row.as_bytes();
+        if row.len() != TEMPLATEWIDTH {
+            return Err(format!("row {} must be {} wide", y, TEMPLATEWIDTH));
+        }
+        for (x, &cell) in row.iter().enumerate() {
+            if cell != BLANK && cell != BLOCK {
+                return Err(format!("row {} may only contain '.' and 'O'", y));
+            }
+            template[y][x] = cell;
+        }
+    }
+    if template.iter().flatten().all(|&cell| cell == BLANK) {
+        return Err("has no blocks".to_string());
+    }
+    Ok(template)
+}
+
+/// The template's blocks moved as close to the origin as they go.
+fn normalized_cells(template: &Template) -> HashSet<(isize, isize)> {
+    let mut cells = Vec::new();
+    for (y, row) in template.iter().enumerate() {
+        for (x, &cell) in row.iter().enumerate() {
+            if cell != BLANK {
+                cells.push((x as isize, y as isize));
+            }
+        }
+    }
+    normalize(cells)
+}
+
+fn normalize(cells: Vec<(isize, isize)>) -> HashSet<(isize, isize)> {
+    let min_x = cells.iter().map(|&(x, _)| x).min().unwrap_or(0);
+    let min_y = cells.iter().map(|&(_, y)| y).min().unwrap_or(0);
+    cells
+        .into_iter()
+        .map(|(x, y)| (x - min_x, y - min_y))
+        .collect()
+}
+
+fn rotated_cw(cells: &HashSet<(isize, isize)>) -> HashSet<(isize, isize)> {
+    // With y pointing down, a clockwise turn takes (x, y) to (-y, x).
+    normalize(cells.iter().map(|&(x, y)| (-y, x)).collect())
+}
+
+fn is_connected(cells: &HashSet<(isize, isize)>) -> bool {
+    let start = match cells.iter().next() {
+        Some(&start) => start,
+        None => return false,
+    };
+    let mut seen = HashSet::new();
+    let mut stack = vec![start];
+    while let Some((x, y)) = stack.pop() {
+        if !cells.contains(&(x, y)) || !seen.insert((x, y)) {
+            continue;
+        }
+        stack.extend(&[(x - 1, y), (x + 1, y), (x, y - 1), (x, y + 1)]);
+    }
+    seen.len() == cells.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_built_in_shapes() {
+        for (set, count, blocks) in [(load(None).unwrap(), 7, 4), (pentominoes(), 18, 5)].iter() {
+            assert_eq!(set.shapes.len(), *count, "{}", set.name);
+            for shape in &set.shapes {
+                for template in shape.template() {
+                    assert_eq!(normalized_cells(template).len(), *blocks, "{}", shape.name);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_an_inconsistent_rotation() {
+        // The second rotation is the first turned counterclockwise.
+        let contents = r#"
+            [[shape]]
+            name = "L"
+            rotations = [
+                [".....", "...O.", ".OOO.", ".....", "....."],
+                [".....", ".OO..", "..O..", "..O..", "....."],
+            ]
+        "#;
+        match parse(contents, Path::new("bad.toml")) {
+            Err(ConfigError::Invalid(_, reason)) => {
+                assert_eq!(
+                    reason,
+                    "shape L: rotation 1 is not rotation 0 turned clockwise"
+                )
+            }
+            _ => panic!("inconsistent rotations were accepted"),
+        }
+    }
+}
@@ -0,0 +1,324 @@
+use crate::config::ConfigError;
+use crate::shapes::{BlockColors, ShapeSet};
+use crate::Block;
+use graphics::types::Color;
+use graphics::{Context, Ellipse, Image, ImageSize, Line, Polygon, Rectangle};
+use opengl_graphics::{GlGraphics, Texture, TextureSettings};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+// Skins that come with the game, by name, with their sprite sheets.
+const BUILT_IN: [(&str, &str, &[u8]); 1] = [(
+    "bevel",
+    include_str!("../skins/bevel/skin.toml"),
+    include_bytes!("../skins/bevel/blocks.png"),
+)];
+
+// Connected textures have one column per combination of links.
+const CONNECTED_COLUMNS: u32 = 16;
+
+// Block patterns, picked by shape index. Shapes past the end reuse them
+// with a mark in the corner.
+#[derive(Clone, Copy)]
+enum Pattern {
+    Dot,
+    Bar,
+    Pillar,
+    Plus,
+    Cross,
+    Frame,
+    Diamond,
+    Triangle,
+    Ring,
+}
+
+const PATTERNS: [Pattern; 9] = [
+    Pattern::Dot,
+    Pattern::Bar,
+    Pattern::Pillar,
+    Pattern::Plus,
+    Pattern::Cross,
+    Pattern::Frame,
+    Pattern::Diamond,
+    Pattern::Triangle,
+    Pattern::Ring,
+];
+
+/// How blocks are drawn: two flat rectangles, or tiles from a sprite sheet.
+pub enum Skin {
+    Flat,
+    Sprites(Sprites),
+}
+
+pub struct Sprites {
+    texture: Texture,
+    tile_size: f64,
+    connected: bool,
+    default_row: usize,
+    garbage_row: usize,
+    // Sheet rows by shape set name, then shape name.
+    rows: HashMap<String, HashMap<String, usize>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SkinData {
+    /// The sprite sheet, relative to the skin file.
+    image: PathBuf,
+    tile_size: u32,
+    /// Whether each row has a tile for every combination of neighbouring
+    /// blocks from the same piece, indexed by `Block::links`.
+    #[serde(default)]
+    connected: bool,
+    /// Drawn tinted with the piece color for shapes without their own row.
+    default_row: usize,
+    garbage_row: usize,
+    #[serde(default)]
+    rows: HashMap<String, HashMap<String, usize>>,
+}
+
+/// Where user skins are looked up by name.
+pub fn user_dir() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("tetris").join("skins"))
+}
+
+/// Loads the flat skin, a built-in skin, a skin by name from the user
+/// skins directory, or a skin file by path. Needs a GL context for the
+/// sprite sheet.
+pub fn load(name: &str) -> Result<Skin, ConfigError> {
+    if name == "flat" {
+        return Ok(Skin::Flat);
+    }
+    if let Some((_, contents, sheet)) = BUILT_IN.iter().find(|(built_in, _, _)| *built_in == name) {
+        let path = Path::new(name).join("skin.toml");
+        return parse(contents, &path, |_| {
+            let image = image::load_from_memory(sheet).map_err(|err| err.to_string())?;
+            Ok(Texture::from_image(
+                &image.to_rgba(),
+                &TextureSettings::new(),
+            ))
+        });
+    }
+    let path = if name.ends_with(".toml") {
+        PathBuf::from(name)
+    } else {
+        match user_dir() {
+            Some(dir) => dir.join(name).join("skin.toml"),
+            None => {
+                return Err(ConfigError::Invalid(
+                    PathBuf::from(name),
+                    "no such skin".to_string(),
+                ))
+            }
+        }
+    };
+    let contents = fs::read_to_string(&path).map_err(|err| ConfigError::Read(path.clone(), err))?;
+    parse(&contents, &path, |image| {
+        Texture::from_path(image, &TextureSettings::new())
+    })
+}
+
+// Reads a skin file at `path`, loading its sprite sheet with `texture`.
+fn parse(
+    contents: &str,
+    path: &Path,
+    texture: impl FnOnce(&Path) -> Result<Texture, String>,
+) -> Result<Skin, ConfigError> {
+    let path = path.to_path_buf();
+    let data: SkinData =
+        toml::from_str(contents).map_err(|err| ConfigError::Parse(path.clone(), err))?;
+    let invalid = |reason: String| ConfigError::Invalid(path.clone(), reason);
+    if data.tile_size == 0 {
+        return Err(invalid("tile_size must be positive".to_string()));
+    }
+    let image = path
+        .parent()
+        .unwrap_or_else(|| Path::new(""))
+        .join(&data.image);
+    let texture = texture(&image)
+        .map_err(|err| invalid(format!("could not load {}: {}", image.display(), err)))?;
+    let (width, height) = texture.get_size();
+    let columns = if data.connected { CONNECTED_COLUMNS } else { 1 };
+    if width < columns * data.tile_size {
+        return Err(invalid(format!(
+            "{} must be at least {} pixels wide for {} columns",
+            image.display(),
+            columns * data.tile_size,
+            columns
+        )));
+    }
+    let last_row = data
+        .rows
+        .values()
+        .flat_map(|rows| rows.values())
+        .chain(&[data.default_row, data.garbage_row])
+        .max()
+        .cloned()
+        .unwrap_or(0);
+    if height < (last_row as u32 + 1) * data.tile_size {
+        return Err(invalid(format!(
+            "{} is too short for row {}",
+            image.display(),
+            last_row
+        )));
+    }
+    Ok(Skin::Sprites(Sprites {
+        texture,
+        tile_size: data.tile_size as f64,
+        connected: data.connected,
+        default_row: data.default_row,
+        garbage_row: data.garbage_row,
+        rows: data.rows,
+    }))
+}
+
+/// Draws the blocks of one shape set with a skin.
+pub struct BlockPainter {
+    pub skin: Rc<Skin>,
+    palette: Vec<BlockColors>,
+    // The sheet row for each shape in the set, if it has its own.
+    rows: Vec<Option<usize>>,
+    patterns: bool,
+}
+
+impl BlockPainter {
+    pub fn new(skin: &Rc<Skin>, shapes: &ShapeSet, patterns: bool) -> BlockPainter {
+        let rows = match skin.as_ref() {
+            Skin::Flat => Vec::new(),
+            Skin::Sprites(sprites) => shapes
+                .shapes
+                .iter()
+                .map(|shape| {
+                    let rows = sprites.rows.get(&shapes.name)?;
+                    rows.get(shape.name()).cloned()
+                })
+                .collect(),
+        };
+        BlockPainter {
+            skin: skin.clone(),
+            palette: shapes.palette.clone(),
+            rows,
+            patterns,
+        }
+    }
+
+    pub fn draw(
+        &self,
+        block: Block,
+        px: f64,
+        py: f64,
+        box_size: f64,
+        c: &Context,
+        g: &mut GlGraphics,
+    ) {
+        let (border, fill) = self.palette[block.color];
+        match self.skin.as_ref() {
+            Skin::Flat => {
+                let border_rect = [px + 1.0, py + 1.0, box_size - 1.0, box_size - 1.0];
+                let box_rect = [px + 1.0, py + 1.0, box_size - 4.0, box_size - 4.0];
+                Rectangle::new(border).draw(border_rect, &c.draw_state, c.transform, g);
+                Rectangle::new(fill).draw(box_rect, &c.draw_state, c.transform, g);
+            }
+            Skin::Sprites(sprites) => {
+                let own_row = block.shape.and_then(|shape| self.rows[shape]);
+                let (row, tint) = match (block.shape, own_row) {
+                    (None, _) => (sprites.garbage_row, [1.0; 4]),
+                    (Some(_), Some(row)) => (row, [1.0; 4]),
+                    (Some(_), None) => (sprites.default_row, fill),
+                };
+                let column = if sprites.connected { block.links } else { 0 };
+                let size = sprites.tile_size;
+                Image::new_color(tint)
+                    .src_rect([column as f64 * size, row as f64 * size, size, size])
+                    .rect([px + 1.0, py + 1.0, box_size - 1.0, box_size - 1.0])
+                    .draw(&sprites.texture, &c.draw_state, c.transform, g);
+            }
+        }
+        if let (true, Some(shape)) = (self.patterns, block.shape) {
+            draw_pattern(shape, contrast(fill), px, py, box_size, c, g);
+        }
+    }
+}
+
+// Dark marks on light blocks and light marks on dark ones.
+fn contrast(fill: Color) -> Color {
+    let luminance = 0.299 * fill[0] + 0.587 * fill[1] + 0.114 * fill[2];
+    if luminance > 0.5 {
+        [0.0, 0.0, 0.0, 0.6]
+    } else {
+        [1.0, 1.0, 1.0, 0.6]
+    }
+}
+
+fn draw_pattern(
+    shape: usize,
+    color: Color,
+    px: f64,
+    py: f64,
+    box_size: f64,
+    c: &Context,
+    g: &mut GlGraphics,
+) {
+    // Inside the block's border, in a square from (x, y) to (x + s, y + s).
+    let s = (box_size - 4.0) * 0.6;
+    let x = px + 1.0 + (box_size - 4.0 - s) / 2.0;
+    let y = py + 1.0 + (box_size - 4.0 - s) / 2.0;
+    let (mid_x, mid_y) = (x + s / 2.0, y + s / 2.0);
+    let width = (box_size / 20.0).max(0.5);
+    let line = Line::new(color, width);
+    let (ds, t) = (&c.draw_state, c.transform);
+    match PATTERNS[shape % PATTERNS.len()] {
+        Pattern::Dot => {
+            let r = s / 5.0;
+            Ellipse::new(color).draw([mid_x - r, mid_y - r, 2.0 * r, 2.0 * r], ds, t, g);
+        }
+        Pattern::Bar => line.draw([x, mid_y, x + s, mid_y], ds, t, g),
+        Pattern::Pillar => line.draw([mid_x, y, mid_x, y + s], ds, t, g),
+        Pattern::Plus => {
+            line.draw([x, mid_y, x + s, mid_y], ds, t, g);
+            line.draw([mid_x, y, mid_x, y + s], ds, t, g);
+        }
+        Pattern::Cross => {
+            line.draw([x, y, x + s, y + s], ds, t, g);
+            line.draw([x + s, y, x, y + s], ds, t, g);
+        }
+        Pattern::Frame => {
+            Rectangle::new_border(color, width).draw([x, y, s, s], ds, t, g);
+        }
+        Pattern::Diamond => {
+            let points = [[mid_x, y], [x + s, mid_y], [mid_x, y + s], [x, mid_y]];
+            Polygon::new(color).draw(&points, ds, t, g);
+        }
+        Pattern::Triangle => {
+            let points = [[mid_x, y], [x + s, y + s], [x, y + s]];
+            Polygon::new(color).draw(&points, ds, t, g);
+        }
+        Pattern::Ring => {
+            Ellipse::new_border(color, width).draw([x, y, s, s], ds, t, g);
+        }
+    }
+    if shape >= PATTERNS.len() {
+        let r = s / 6.0;
+        Rectangle::new(color).draw([px + 2.0, py + 2.0, r, r], ds, t, g);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_built_in_skins() {
+        for (name, contents, sheet) in BUILT_IN.iter() {
+            let data: SkinData = toml::from_str(contents).unwrap();
+            let image = image::load_from_memory(sheet).unwrap().to_rgba();
+            let columns = if data.connected { CONNECTED_COLUMNS } else { 1 };
+            assert!(image.width() >= columns * data.tile_size, "{}", name);
+            let rows = data.default_row.max(data.garbage_row) as u32 + 1;
+            assert!(image.height() >= rows * data.tile_size, "{}", name);
+        }
+    }
+}
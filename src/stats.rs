@@ -0,0 +1,259 @@
+use crate::config::{self, ConfigError};
+use crate::input::ActionEvents;
+use crate::menu::MenuInput;
+use crate::{CenterText, GameEvent, Layout};
+use graphics::text::Text;
+use graphics::Transformed;
+use opengl_graphics::{GlGraphics, GlyphCache};
+use piston::input::GenericEvent;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::PathBuf;
+
+/// Counts from one game, or the lifetime totals kept in `stats.toml` in the
+/// user data directory.
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Stats {
+    pub games: u32,
+    /// Time played, leaving out pauses.
+    pub time_ms: u64,
+    pub pieces: u32,
+    /// Presses of anything that moves, rotates, drops or holds a piece.
+    pub inputs: u32,
+    /// Line clears by size, starting with singles.
+    pub clears: Vec<u32>,
+    pub t_spins: u32,
+    /// The longest run of clearing pieces, not counting the first.
+    pub max_combo: u32,
+    pub holds: u32,
+}
+
+impl Stats {
+    fn path() -> Option<PathBuf> {
+        dirs::data_dir().map(|dir| dir.join("tetris").join("stats.toml"))
+    }
+
+    /// Reads the lifetime totals, starting from zero when there are none.
+    pub fn load() -> Result<Stats, ConfigError> {
+        config::load_file(Stats::path())
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        config::save_file(self, Stats::path())
+    }
+
+    pub fn record(&mut self, event: &GameEvent) {
+        match *event {
+            GameEvent::Held => self.holds += 1,
+            GameEvent::Locked { t_spin } => {
+                self.pieces += 1;
+                if t_spin {
+                    self.t_spins += 1;
+                }
+            }
+            GameEvent::LinesCleared { lines, combo, .. } => {
+                let size = lines as usize;
+                if self.clears.len() < size {
+                    self.clears.resize(size, 0);
+                }
+                self.clears[size - 1] += 1;
+                self.max_combo = self.max_combo.max(combo);
+            }
+            _ => {}
+        }
+    }
+
+    /// Adds a game's counts to these totals.
+    pub fn add(&mut self, game: &Stats) {
+        self.games += game.games;
+        self.time_ms += game.time_ms;
+        self.pieces += game.pieces;
+        self.inputs += game.inputs;
+        if self.clears.len() < game.clears.len() {
+            self.clears.resize(game.clears.len(), 0);
+        }
+        for (total, count) in self.clears.iter_mut().zip(game.clears.iter()) {
+            *total += count;
+        }
+        self.t_spins += game.t_spins;
+        self.max_combo = self.max_combo.max(game.max_combo);
+        self.holds += game.holds;
+    }
+
+    pub fn pieces_per_second(&self) -> f64 {
+        if self.time_ms == 0 {
+            return 0.0;
+        }
+        self.pieces as f64 * 1000.0 / self.time_ms as f64
+    }
+
+    pub fn keys_per_piece(&self) -> f64 {
+        if self.pieces == 0 {
+            return 0.0;
+        }
+        self.inputs as f64 / self.pieces as f64
+    }
+
+    // Labels and values in the order they're shown, with clears listed up
+    // to the given size so two sets of rows line up.
+    fn rows(&self, clear_sizes: usize) -> Vec<(String, String)> {
+        let secs = self.time_ms / 1000;
+        let mut rows = vec![
+            ("Games".to_string(), self.games.to_string()),
+            (
+                "Time".to_string(),
+                format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60),
+            ),
+            ("Pieces".to_string(), self.pieces.to_string()),
+            (
+                "Pieces per second".to_string(),
+                format!("{:.2}", self.pieces_per_second()),
+            ),
+            (
+                "Keys per piece".to_string(),
+                format!("{:.2}", self.keys_per_piece()),
+            ),
+        ];
+        for size in 1..=clear_sizes {
+            let label = match size {
+                1 => "Singles".to_string(),
+                2 => "Doubles".to_string(),
+                3 => "Triples".to_string(),
+                4 => "Tetrises".to_string(),
+                _ => format!("{}-line clears", size),
+            };
+            let count = self.clears.get(size - 1).cloned().unwrap_or(0);
+            rows.push((label, count.to_string()));
+        }
+        rows.push(("T-spins".to_string(), self.t_spins.to_string()));
+        rows.push(("Max combo".to_string(), self.max_combo.to_string()));
+        rows.push(("Holds".to_string(), self.holds.to_string()));
+        rows
+    }
+}
+
+/// A game's stats next to the lifetime totals, or the totals on their own.
+pub struct StatsScreen {
+    title: String,
+    details: Vec<String>,
+    game: Option<Stats>,
+    lifetime: Stats,
+}
+
+impl StatsScreen {
+    pub fn new(
+        title: &str,
+        details: Vec<String>,
+        game: Option<Stats>,
+        lifetime: &Stats,
+    ) -> StatsScreen {
+        StatsScreen {
+            title: title.to_string(),
+            details,
+            game,
+            lifetime: lifetime.clone(),
+        }
+    }
+
+    /// Returns true to play again after a game, false to go back.
+    pub fn run<E: GenericEvent>(
+        &mut self,
+        e: &E,
+        actions: &ActionEvents,
+        layout: &Layout,
+        gl: &mut GlGraphics,
+        glyphs: &mut GlyphCache,
+    ) -> Option<bool> {
+        let choice = match MenuInput::read(e, actions) {
+            Some(MenuInput::Select) => Some(self.game.is_some()),
+            Some(MenuInput::Back) => Some(false),
+            _ => None,
+        };
+        if let Some(args) = e.render_args() {
+            gl.draw(args.viewport(), |c, g| {
+                let theme = &layout.theme;
+                graphics::clear(theme.background, g);
+                let c = layout.fit(c, args.window_size);
+                let center = layout.width / 2.0;
+                let mut font = Text::new_color(theme.text, 40);
+                font.draw_center(
+                    &self.title,
+                    glyphs,
+                    &c.draw_state,
+                    c.transform.trans(center, 50.0),
+                    g,
+                )
+                .expect("Unable to draw string");
+                font.font_size = 16;
+                font.color = theme.text_shadow;
+                for (i, detail) in self.details.iter().enumerate() {
+                    font.draw_center(
+                        detail,
+                        glyphs,
+                        &c.draw_state,
+                        c.transform.trans(center, 80.0 + i as f64 * 20.0),
+                        g,
+                    )
+                    .expect("Unable to draw string");
+                }
+                let top = 100.0 + self.details.len() as f64 * 20.0;
+                // Singles through tetrises are always listed, bigger clears
+                // only once they happen.
+                let clear_sizes = self
+                    .game
+                    .iter()
+                    .chain(Some(&self.lifetime))
+                    .map(|stats| stats.clears.len())
+                    .fold(4, usize::max);
+                let lifetime = self.lifetime.rows(clear_sizes);
+                let game = self.game.as_ref().map(|game| game.rows(clear_sizes));
+                let spacing =
+                    ((layout.height - top - 70.0) / (lifetime.len() + 1) as f64).min(22.0);
+                let mut columns = vec![("", layout.width * 0.2)];
+                if game.is_some() {
+                    columns.push(("This game", layout.width * 0.55));
+                }
+                columns.push(("Lifetime", layout.width * 0.75));
+                font.color = theme.text;
+                for (heading, x) in columns.iter().skip(1) {
+                    font.draw(
+                        heading,
+                        glyphs,
+                        &c.draw_state,
+                        c.transform.trans(*x, top),
+                        g,
+                    )
+                    .expect("Unable to draw string");
+                }
+                font.color = theme.text_shadow;
+                for (i, (label, total)) in lifetime.iter().enumerate() {
+                    let y = top + (i + 1) as f64 * spacing;
+                    let mut cells = vec![label.as_str()];
+                    if let Some(game) = &game {
+                        cells.push(game.get(i).map_or("", |(_, value)| value.as_str()));
+                    }
+                    cells.push(total);
+                    for (cell, (_, x)) in cells.iter().zip(columns.iter()) {
+                        font.draw(cell, glyphs, &c.draw_state, c.transform.trans(*x, y), g)
+                            .expect("Unable to draw string");
+                    }
+                }
+                let hint = if self.game.is_some() {
+                    "Enter: play again  Esc: menu"
+                } else {
+                    "Esc: back"
+                };
+                font.draw_center(
+                    hint,
+                    glyphs,
+                    &c.draw_state,
+                    c.transform.trans(center, layout.height - 30.0),
+                    g,
+                )
+                .expect("Unable to draw string");
+            });
+        }
+        choice
+    }
+}
@@ -0,0 +1,195 @@
+use crate::config::ConfigError;
+use crate::shapes::{parse_color, BlockColors};
+use crate::{
+    BGCOLOR, BLACK, BORDERCOLOR, DANGERCOLOR, GRAY, LIGHTMIDGRAY, MIDGRAY, TEXTCOLOR,
+    TEXTSHADOWCOLOR, WHITE,
+};
+use graphics::types::Color;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const BUILT_IN: [(&str, &str); 7] = [
+    ("classic", include_str!("../themes/classic.toml")),
+    ("guideline", include_str!("../themes/guideline.toml")),
+    ("midnight", include_str!("../themes/midnight.toml")),
+    ("paper", include_str!("../themes/paper.toml")),
+    ("deuteranopia", include_str!("../themes/deuteranopia.toml")),
+    ("protanopia", include_str!("../themes/protanopia.toml")),
+    ("tritanopia", include_str!("../themes/tritanopia.toml")),
+];
+
+/// The colors everything on screen is drawn in.
+#[derive(Clone)]
+pub struct Theme {
+    pub border: Color,
+    pub background: Color,
+    pub text: Color,
+    pub text_shadow: Color,
+    /// What the border pulses to when the stack gets near the top.
+    pub danger: Color,
+    pub garbage: BlockColors,
+    // Piece colors by shape set name, then shape name. Shapes not listed
+    // keep the colors from their shape file.
+    pieces: HashMap<String, HashMap<String, BlockColors>>,
+}
+
+impl Default for Theme {
+    fn default() -> Theme {
+        Theme {
+            border: BORDERCOLOR,
+            background: BGCOLOR,
+            text: TEXTCOLOR,
+            text_shadow: TEXTSHADOWCOLOR,
+            danger: DANGERCOLOR,
+            garbage: (MIDGRAY, LIGHTMIDGRAY),
+            pieces: HashMap::new(),
+        }
+    }
+}
+
+impl Theme {
+    /// The theme in black and white, apart from its piece and danger
+    /// colors. Pieces are brightened by `ShapeSet::with_high_contrast`.
+    pub fn high_contrast(&self) -> Theme {
+        Theme {
+            border: WHITE,
+            background: BLACK,
+            text: WHITE,
+            text_shadow: GRAY,
+            ..self.clone()
+        }
+    }
+
+    pub fn piece_colors(&self, shape_set: &str, shape: &str) -> Option<BlockColors> {
+        self.pieces.get(shape_set)?.get(shape).cloned()
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ThemeData {
+    /// The built-in theme this one changes. User themes default to the
+    /// guideline theme.
+    base: Option<String>,
+    border: Option<String>,
+    background: Option<String>,
+    text: Option<String>,
+    text_shadow: Option<String>,
+    danger: Option<String>,
+    garbage: Option<(String, String)>,
+    #[serde(default)]
+    pieces: HashMap<String, HashMap<String, (String, String)>>,
+}
+
+/// Where user themes are looked up by name.
+pub fn user_dir() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("tetris").join("themes"))
+}
+
+/// Loads a built-in theme by name, a user theme from the themes directory
+/// by name, or a theme file by path.
+pub fn load(name: &str) -> Result<Theme, ConfigError> {
+    if let Some(theme) = built_in(name) {
+        return Ok(theme);
+    }
+    let path = if name.ends_with(".toml") {
+        PathBuf::from(name)
+    } else {
+        match user_dir() {
+            Some(dir) => dir.join(format!("{}.toml", name)),
+            None => {
+                return Err(ConfigError::Invalid(
+                    PathBuf::from(name),
+                    "no such theme".to_string(),
+                ))
+            }
+        }
+    };
+    let contents = fs::read_to_string(&path).map_err(|err| ConfigError::Read(path.clone(), err))?;
+    parse(&contents, &path, Some("guideline"))
+}
+
+fn built_in(name: &str) -> Option<Theme> {
+    let (_, contents) = BUILT_IN.iter().find(|(built_in, _)| *built_in == name)?;
+    Some(
+        parse(contents, Path::new(name), None)
+            .unwrap_or_else(|err| panic!("Built-in theme is broken: {}", err)),
+    )
+}
+
+fn parse(contents: &str, path: &Path, default_base: Option<&str>) -> Result<Theme, ConfigError> {
+    let data: ThemeData =
+        toml::from_str(contents).map_err(|err| ConfigError::Parse(path.to_path_buf(), err))?;
+    let invalid = |reason: String| ConfigError::Invalid(path.to_path_buf(), reason);
+    let mut theme = match data.base.as_deref().or(default_base) {
+        Some(base) => built_in(base)
+            .ok_or_else(|| invalid(format!("base {:?} is not a built-in theme", base)))?,
+        None => Theme::default(),
+    };
+    let color = |name: &str, hex: &str| {
+        parse_color(hex)
+            .ok_or_else(|| invalid(format!("{} {:?} is not a #rrggbb color", name, hex)))
+    };
+    let pair = |name: &str, (border, fill): &(String, String)| -> Result<BlockColors, _> {
+        Ok((color(name, border)?, color(name, fill)?))
+    };
+    if let Some(hex) = &data.border {
+        theme.border = color("border", hex)?;
+    }
+    if let Some(hex) = &data.background {
+        theme.background = color("background", hex)?;
+    }
+    if let Some(hex) = &data.text {
+        theme.text = color("text", hex)?;
+    }
+    if let Some(hex) = &data.text_shadow {
+        theme.text_shadow = color("text_shadow", hex)?;
+    }
+    if let Some(hex) = &data.danger {
+        theme.danger = color("danger", hex)?;
+    }
+    if let Some(colors) = &data.garbage {
+        theme.garbage = pair("garbage", colors)?;
+    }
+    for (shape_set, shapes) in data.pieces.iter() {
+        for (shape, colors) in shapes.iter() {
+            let colors = pair(&format!("pieces.{}.{}", shape_set, shape), colors)?;
+            theme
+                .pieces
+                .entry(shape_set.clone())
+                .or_default()
+                .insert(shape.clone(), colors);
+        }
+    }
+    Ok(theme)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shapes;
+
+    #[test]
+    fn parses_built_in_themes() {
+        let sets = [shapes::load(None).unwrap(), shapes::pentominoes()];
+        for &(name, _) in BUILT_IN.iter() {
+            let theme = load(name).unwrap();
+            // Colors for a shape that doesn't exist would never be used.
+            for (set_name, pieces) in &theme.pieces {
+                let set = sets.iter().find(|set| set.name == *set_name);
+                let set = set.unwrap_or_else(|| panic!("{}: no shape set {}", name, set_name));
+                for shape in pieces.keys() {
+                    assert!(
+                        set.shapes.iter().any(|other| other.name() == shape),
+                        "{}: no shape {} in {}",
+                        name,
+                        shape,
+                        set_name
+                    );
+                }
+            }
+        }
+    }
+}